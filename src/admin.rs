@@ -0,0 +1,409 @@
+//! Authentication of requests made by admins of this server and admin-only routes.
+//!
+//! Admins authenticate using the token set with "--admin-token". It can be
+//! passed either as "Authorization: Bearer <token>" header or as the query
+//! parameter "admin_token" (browsers can't set headers for websockets).
+
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use color_eyre::eyre::Context;
+use ipnet::Ipv6Net;
+use serde::Deserialize;
+
+use crate::admin_ws::{AdminEvent, ModerationEvent};
+use crate::bans::{self, Ban, BanInfo};
+use crate::canvas::CanvasState;
+use crate::canvas_processor::{ProcessorCommand, RollbackResult};
+use crate::pixel_history::RollbackTarget;
+use crate::pixel_provenance::unix_millis;
+use crate::protected_regions::{self, ProtectedRegion};
+use crate::SERVER_CONFIG;
+
+type AdminError = (StatusCode, String);
+
+/// Check whether the request is authenticated as admin.
+/// Always false if no admin token was configured.
+pub fn is_admin(headers: &HeaderMap, query_token: Option<&str>) -> bool {
+    let server_config = SERVER_CONFIG.lock().unwrap();
+    let Some(admin_token) = server_config.admin_token.as_deref() else {
+        return false;
+    };
+
+    let header_token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    [header_token, query_token]
+        .into_iter()
+        .flatten()
+        .any(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()))
+}
+
+/// Compare without returning early to not leak the token through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Deserialize)]
+struct AdminQueryParams {
+    admin_token: Option<String>,
+}
+
+/// Extractor which rejects the request if it isn't authenticated as admin
+pub struct RequireAdmin;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequireAdmin {
+    type Rejection = AdminError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query_token = Query::<AdminQueryParams>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|Query(params)| params.admin_token);
+        if is_admin(&parts.headers, query_token.as_deref()) {
+            Ok(RequireAdmin)
+        } else {
+            Err((
+                StatusCode::UNAUTHORIZED,
+                String::from("Missing or wrong admin token!"),
+            ))
+        }
+    }
+}
+
+/// Routes to be nested under /admin
+pub fn router() -> Router<Arc<CanvasState>> {
+    let router = Router::new()
+        .route(
+            "/protected_regions",
+            get(get_protected_regions)
+                .put(put_protected_regions)
+                .post(post_protected_region),
+        )
+        .route("/protected_regions/:name", delete(delete_protected_region))
+        .route(
+            "/bans",
+            get(get_bans)
+                .put(put_bans)
+                .post(post_ban)
+                .delete(delete_ban),
+        )
+        .route("/rollback", post(post_rollback))
+        .route("/ws", get(crate::admin_ws::get_admin_ws));
+    #[cfg(feature = "per_user_pps")]
+    let router = router
+        .route("/names/:user_id", delete(delete_display_name))
+        .route("/config", get(get_config).put(put_config));
+    router
+}
+
+/// Settings which admins can change while the server is running
+#[cfg(feature = "per_user_pps")]
+#[derive(serde::Serialize, Deserialize)]
+struct AdminConfig {
+    tracking: crate::per_user_pps::TrackingOptions,
+}
+
+#[cfg(feature = "per_user_pps")]
+async fn get_config(_: RequireAdmin) -> Result<Json<AdminConfig>, AdminError> {
+    let tracking = SERVER_CONFIG.lock().unwrap().tracking_options;
+    match tracking {
+        Some(tracking) => Ok(Json(AdminConfig { tracking })),
+        None => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            String::from("Per user tracking isn't set up yet"),
+        )),
+    }
+}
+
+/// Replace the settings (applied by the canvas processor with the next frame)
+#[cfg(feature = "per_user_pps")]
+async fn put_config(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Json(config): Json<AdminConfig>,
+) -> Result<Json<AdminConfig>, AdminError> {
+    config
+        .tracking
+        .validate()
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    info!("Admin: Per user pps tracking is now: {:?}", config.tracking);
+    SERVER_CONFIG.lock().unwrap().tracking_options = Some(config.tracking);
+    canvas_state.send_processor_command(ProcessorCommand::SetTrackingOptions(config.tracking));
+    Ok(Json(config))
+}
+
+async fn get_protected_regions(_: RequireAdmin) -> Json<Vec<ProtectedRegion>> {
+    Json(SERVER_CONFIG.lock().unwrap().protected_regions.clone())
+}
+
+/// Replace all protected regions
+async fn put_protected_regions(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Json(mut regions): Json<Vec<ProtectedRegion>>,
+) -> Result<Json<Vec<ProtectedRegion>>, AdminError> {
+    protected_regions::prepare_all(&mut regions)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    update_protected_regions(&canvas_state, |existing| *existing = regions).await
+}
+
+/// Add a protected region or replace the one with the same name
+async fn post_protected_region(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Json(mut region): Json<ProtectedRegion>,
+) -> Result<Json<Vec<ProtectedRegion>>, AdminError> {
+    region
+        .prepare()
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    update_protected_regions(&canvas_state, |regions| {
+        regions.retain(|existing| existing.name != region.name);
+        regions.push(region);
+    })
+    .await
+}
+
+async fn delete_protected_region(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<ProtectedRegion>>, AdminError> {
+    if !SERVER_CONFIG
+        .lock()
+        .unwrap()
+        .protected_regions
+        .iter()
+        .any(|region| region.name == name)
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("No protected region named {name:?}"),
+        ));
+    }
+    update_protected_regions(&canvas_state, |regions| {
+        regions.retain(|region| region.name != name)
+    })
+    .await
+}
+
+/// Apply a change to the protected regions and persist them if they were loaded from a file
+async fn update_protected_regions(
+    canvas_state: &CanvasState,
+    change: impl FnOnce(&mut Vec<ProtectedRegion>),
+) -> Result<Json<Vec<ProtectedRegion>>, AdminError> {
+    let regions = {
+        let mut server_config = SERVER_CONFIG.lock().unwrap();
+        change(&mut server_config.protected_regions);
+        protected_regions::publish(&server_config.protected_regions);
+        server_config.protected_regions.clone()
+    };
+    let names: Vec<_> = regions.iter().map(|region| region.name.clone()).collect();
+    info!("Admin: Protected regions are now: {names:?}");
+    canvas_state.publish_admin_event(AdminEvent::Moderation(Arc::new(
+        ModerationEvent::ProtectedRegionsChanged { names },
+    )));
+
+    tokio::task::spawn_blocking(protected_regions::save)
+        .await
+        .context("Saving protected regions")
+        .and_then(|result| result)
+        .map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Applied but failed to save protected regions: {err:#}"),
+            )
+        })?;
+    Ok(Json(regions))
+}
+
+async fn get_bans(_: RequireAdmin) -> Json<Vec<BanInfo>> {
+    let now_unix_ms = unix_millis(std::time::SystemTime::now());
+    Json(
+        SERVER_CONFIG
+            .lock()
+            .unwrap()
+            .bans
+            .iter()
+            .map(|ban| ban.info(now_unix_ms))
+            .collect(),
+    )
+}
+
+/// Replace all bans
+async fn put_bans(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Json(bans): Json<Vec<Ban>>,
+) -> Result<Json<Vec<BanInfo>>, AdminError> {
+    update_bans(&canvas_state, |existing| {
+        let mut bans = bans;
+        bans::prepare_all(&mut bans, existing);
+        *existing = bans;
+    })
+    .await
+}
+
+/// Add a ban or replace the one with the same prefix
+async fn post_ban(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Json(ban): Json<Ban>,
+) -> Result<Json<Vec<BanInfo>>, AdminError> {
+    update_bans(&canvas_state, |bans| {
+        bans.push(ban);
+        let existing = bans.clone();
+        bans::prepare_all(bans, &existing);
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+struct DeleteBanParams {
+    prefix: Ipv6Net,
+}
+
+async fn delete_ban(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Query(params): Query<DeleteBanParams>,
+) -> Result<Json<Vec<BanInfo>>, AdminError> {
+    let prefix = params.prefix.trunc();
+    if !SERVER_CONFIG
+        .lock()
+        .unwrap()
+        .bans
+        .iter()
+        .any(|ban| ban.prefix == prefix)
+    {
+        return Err((StatusCode::NOT_FOUND, format!("{prefix} is not banned")));
+    }
+    update_bans(&canvas_state, |bans| {
+        bans.retain(|ban| ban.prefix != prefix)
+    })
+    .await
+}
+
+/// Apply a change to the bans and persist them if they were loaded from a file
+async fn update_bans(
+    canvas_state: &CanvasState,
+    change: impl FnOnce(&mut Vec<Ban>),
+) -> Result<Json<Vec<BanInfo>>, AdminError> {
+    let bans = {
+        let mut server_config = SERVER_CONFIG.lock().unwrap();
+        change(&mut server_config.bans);
+        bans::publish(&server_config.bans);
+        server_config.bans.clone()
+    };
+    let prefixes: Vec<_> = bans.iter().map(|ban| ban.prefix).collect();
+    info!("Admin: Banned prefixes are now: {prefixes:?}");
+    canvas_state.publish_admin_event(AdminEvent::Moderation(Arc::new(
+        ModerationEvent::BansChanged { prefixes },
+    )));
+
+    tokio::task::spawn_blocking(bans::save)
+        .await
+        .context("Saving bans")
+        .and_then(|result| result)
+        .map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Applied but failed to save bans: {err:#}"),
+            )
+        })?;
+    let now_unix_ms = unix_millis(std::time::SystemTime::now());
+    Ok(Json(bans.iter().map(|ban| ban.info(now_unix_ms)).collect()))
+}
+
+/// Remove an inappropriate display name (the user can claim a new one)
+#[cfg(feature = "per_user_pps")]
+async fn delete_display_name(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Path(user_id): Path<u64>,
+) -> Result<String, AdminError> {
+    let removed_name = crate::display_names::DISPLAY_NAMES
+        .lock()
+        .unwrap()
+        .remove(user_id);
+    let Some(name) = removed_name else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("User {user_id} has no display name"),
+        ));
+    };
+    info!("Admin: Removed display name {name:?} of user {user_id}");
+    canvas_state.publish_admin_event(AdminEvent::Moderation(Arc::new(
+        ModerationEvent::DisplayNameRemoved {
+            user_id,
+            name: name.clone(),
+        },
+    )));
+    tokio::task::spawn_blocking(crate::display_names::save_names)
+        .await
+        .context("Saving display names")
+        .and_then(|result| result)
+        .map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Removed but failed to save display names: {err:#}"),
+            )
+        })?;
+    Ok(format!("Removed display name {name:?} of user {user_id}"))
+}
+
+#[derive(Deserialize)]
+struct RollbackRequest {
+    target: RollbackTarget,
+    /// Unix time in milliseconds
+    from_ms: u64,
+    /// Unix time in milliseconds (defaults to now)
+    to_ms: Option<u64>,
+}
+
+/// Revert all pixels that a user id or prefix wrote in a time range
+async fn post_rollback(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    Json(request): Json<RollbackRequest>,
+) -> Result<Json<RollbackResult>, AdminError> {
+    let to_ms = request
+        .to_ms
+        .unwrap_or_else(|| unix_millis(std::time::SystemTime::now()));
+    info!(
+        "Admin: Requested rollback of {:?} between {} and {to_ms}",
+        request.target, request.from_ms
+    );
+    let (reply_sender, reply_receiver) = tokio::sync::oneshot::channel();
+    canvas_state.send_processor_command(ProcessorCommand::Rollback {
+        target: request.target,
+        from_ms: request.from_ms,
+        to_ms,
+        reply: reply_sender,
+    });
+    let result = reply_receiver.await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            String::from("The canvas processor didn't respond!"),
+        )
+    })?;
+    canvas_state.publish_admin_event(AdminEvent::Moderation(Arc::new(
+        ModerationEvent::Rollback {
+            target: request.target,
+            from_ms: request.from_ms,
+            to_ms,
+            restored_pixels: result.restored_pixels,
+        },
+    )));
+    Ok(Json(result))
+}
@@ -0,0 +1,247 @@
+//! Websocket for admins (/admin/ws) which, unlike /ws, shows raw source addresses.
+//!
+//! The canvas processor and admin routes publish AdminEvents which only get
+//! sent to clients of this websocket (if they enabled them).
+
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        ConnectInfo, State, WebSocketUpgrade,
+    },
+    http::HeaderMap,
+    response::Response,
+};
+use color_eyre::{eyre::Context, Result};
+use image::Rgb;
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::admin::RequireAdmin;
+use crate::canvas::CanvasState;
+use crate::palette::serialize_hex_color;
+use crate::ping_listener::{ListenerCountersSnapshot, LISTENER_COUNTERS};
+use crate::pixel_history::RollbackTarget;
+
+/// Pixels beyond this per canvas update are only counted
+const MAX_PIXELS_PER_BATCH: usize = 4096;
+
+#[derive(Clone)]
+pub enum AdminEvent {
+    PpsUpdate(Arc<AdminPpsInfo>),
+    Pixels(Arc<AdminPixelBatch>),
+    Moderation(Arc<ModerationEvent>),
+}
+
+/// Published once per second
+#[derive(Serialize)]
+pub struct AdminPpsInfo {
+    /// Total
+    pub pps: usize,
+    /// All tracked prefixes (also the ones without pixels in the last second)
+    #[cfg(feature = "per_user_pps")]
+    pub per_prefix: Vec<PrefixPps>,
+    /// All sources which aren't tracked (they share one rate limit and cooldown)
+    #[cfg(feature = "per_user_pps")]
+    pub untracked: UntrackedPps,
+    /// Pings per second dropped by each ban (only bans which were hit)
+    pub ban_hits: Vec<BanHitRate>,
+    pub listener: ListenerCountersSnapshot,
+}
+
+#[cfg(feature = "per_user_pps")]
+#[derive(Serialize)]
+pub struct PrefixPps {
+    pub prefix: Ipv6Net,
+    pub user_id: u64,
+    pub pps: usize,
+    /// Dropped by the rate limit or cooldown
+    pub dropped: usize,
+}
+
+#[cfg(feature = "per_user_pps")]
+#[derive(Serialize)]
+pub struct UntrackedPps {
+    pub pps: usize,
+    /// Dropped by the shared rate limit or cooldown
+    pub dropped: usize,
+}
+
+#[derive(Serialize)]
+pub struct BanHitRate {
+    pub prefix: Ipv6Net,
+    pub pps: usize,
+}
+
+/// Pixels drawn since the last canvas update
+#[derive(Serialize, Default)]
+pub struct AdminPixelBatch {
+    pub pixels: Vec<AdminPixel>,
+    /// Pixels which were drawn but not included (see MAX_PIXELS_PER_BATCH)
+    pub skipped: usize,
+}
+
+impl AdminPixelBatch {
+    /// Add the pixel (or only count it if the batch is full)
+    #[inline]
+    pub fn push(&mut self, pixel: AdminPixel) {
+        if self.pixels.len() < MAX_PIXELS_PER_BATCH {
+            self.pixels.push(pixel);
+        } else {
+            self.skipped += 1;
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AdminPixel {
+    pub source: Ipv6Addr,
+    pub user_id: Option<u64>,
+    pub x: u16,
+    pub y: u16,
+    pub size: u8,
+    #[serde(serialize_with = "serialize_hex_color")]
+    pub color: Rgb<u8>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ModerationEvent {
+    BansChanged {
+        prefixes: Vec<Ipv6Net>,
+    },
+    ProtectedRegionsChanged {
+        names: Vec<String>,
+    },
+    Rollback {
+        target: RollbackTarget,
+        from_ms: u64,
+        to_ms: u64,
+        restored_pixels: usize,
+    },
+    /// The scheduled reset happened anyway
+    ArchiveFailed {
+        error: String,
+    },
+    #[cfg(feature = "per_user_pps")]
+    DisplayNameRemoved {
+        user_id: u64,
+        name: String,
+    },
+}
+
+/// Client -> Server
+#[derive(Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+enum AdminWsRequest {
+    PpsUpdates { enabled: bool },
+    PixelEvents { enabled: bool },
+    ModerationEvents { enabled: bool },
+    GetListenerCountersOnce,
+}
+
+/// Server -> Client
+#[derive(Serialize)]
+#[serde(tag = "message", rename_all = "snake_case")]
+enum AdminWsMessage<'a> {
+    PpsUpdate {
+        #[serde(flatten)]
+        pps_info: &'a AdminPpsInfo,
+    },
+    PixelEvents {
+        #[serde(flatten)]
+        batch: &'a AdminPixelBatch,
+    },
+    ModerationEvent {
+        #[serde(flatten)]
+        event: &'a ModerationEvent,
+    },
+    ListenerCounters {
+        #[serde(flatten)]
+        counters: ListenerCountersSnapshot,
+    },
+    /// This client was too slow and missed some events
+    Lagged { skipped_events: u64 },
+}
+
+pub async fn get_admin_ws(
+    _: RequireAdmin,
+    ws: WebSocketUpgrade,
+    State(canvas_state): State<Arc<CanvasState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let addr = SocketAddr::new(crate::get_real_ip(addr.ip(), &headers), addr.port());
+    ws.on_upgrade(move |mut ws| async move {
+        if let Err(err) = admin_websocket_connection(&mut ws, canvas_state, addr).await {
+            warn!("Admin websocket: Connection to {addr} failed: {err}");
+            ws.close().await.ok();
+        }
+    })
+}
+
+async fn send(ws: &mut WebSocket, message: &AdminWsMessage<'_>) -> Result<()> {
+    let text = serde_json::to_string(message).context("Encode admin message")?;
+    ws.send(Message::Text(text))
+        .await
+        .context("Send admin message")
+}
+
+async fn admin_websocket_connection(
+    ws: &mut WebSocket,
+    canvas_state: Arc<CanvasState>,
+    addr: SocketAddr,
+) -> Result<()> {
+    info!("Admin websocket: {addr} connected");
+    let mut admin_events_receiver = canvas_state.subscribe_to_admin_events();
+
+    let mut pps_updates_enabled = false;
+    let mut pixel_events_enabled = false;
+    let mut moderation_events_enabled = false;
+
+    loop {
+        tokio::select! {
+            admin_event_res = admin_events_receiver.recv() => {
+                match admin_event_res {
+                    Ok(AdminEvent::PpsUpdate(pps_info)) if pps_updates_enabled => {
+                        send(ws, &AdminWsMessage::PpsUpdate { pps_info: &pps_info }).await?;
+                    }
+                    Ok(AdminEvent::Pixels(batch)) if pixel_events_enabled => {
+                        send(ws, &AdminWsMessage::PixelEvents { batch: &batch }).await?;
+                    }
+                    Ok(AdminEvent::Moderation(event)) if moderation_events_enabled => {
+                        send(ws, &AdminWsMessage::ModerationEvent { event: &event }).await?;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped_events)) => {
+                        send(ws, &AdminWsMessage::Lagged { skipped_events }).await?;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+            maybe_ws_message_res = ws.recv() => {
+                let Some(ws_message_res) = maybe_ws_message_res else {
+                    info!("Admin websocket: {addr} closed connection");
+                    return Ok(());
+                };
+                let Message::Text(text) = ws_message_res.context("Websocket message")? else {
+                    continue;
+                };
+                let request: AdminWsRequest = serde_json::from_str(&text).context("Parsing received text as AdminWsRequest")?;
+                match request {
+                    AdminWsRequest::PpsUpdates { enabled } => pps_updates_enabled = enabled,
+                    AdminWsRequest::PixelEvents { enabled } => pixel_events_enabled = enabled,
+                    AdminWsRequest::ModerationEvents { enabled } => moderation_events_enabled = enabled,
+                    AdminWsRequest::GetListenerCountersOnce => {
+                        send(ws, &AdminWsMessage::ListenerCounters { counters: LISTENER_COUNTERS.snapshot() }).await?;
+                    }
+                }
+            }
+        }
+    }
+}
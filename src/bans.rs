@@ -0,0 +1,263 @@
+//! Source prefixes whose pings get dropped before they draw or count.
+//!
+//! Bans can expire and are loaded from a file which gets reloaded when it changes.
+//! Hits are counted per ban to see whether a ban is still needed.
+//!
+//! The ping listener checks each ping against the published BanTable, which
+//! gets replaced whenever the bans change (so it never has to lock anything).
+
+use std::{
+    collections::HashMap,
+    net::Ipv6Addr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwapOption;
+use color_eyre::{eyre::Context, Result};
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+
+use crate::pixel_provenance::unix_millis;
+use crate::SERVER_CONFIG;
+
+/// How often the bans file is checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The current bans as used by the ping listener (see publish)
+static PUBLISHED_BANS: ArcSwapOption<BanTable> = ArcSwapOption::const_empty();
+/// Held while saving (and while the watcher reloads), so an older state can't
+/// overwrite a newer one
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+/// Modification time of the bans file after the last save, so the watcher
+/// doesn't reload what was just saved
+static SAVED_MODIFIED_AT: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ban {
+    pub prefix: Ipv6Net,
+    /// Unix time in seconds. Never expires if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Shared by all clones (and kept on reloads)
+    #[serde(skip)]
+    hits: Arc<BanHits>,
+}
+
+#[derive(Default)]
+struct BanHits {
+    count: AtomicU64,
+    /// Unix time in milliseconds (0 if never hit)
+    last_hit_at_ms: AtomicU64,
+}
+
+/// A ban including how often it was hit (for admins)
+#[derive(Serialize)]
+pub struct BanInfo {
+    #[serde(flatten)]
+    pub ban: Ban,
+    /// Dropped pings since the ban was added or the server started
+    pub hits: u64,
+    pub last_hit_at_ms: Option<u64>,
+    pub expired: bool,
+}
+
+impl Ban {
+    pub fn is_expired(&self, now_unix_ms: u64) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at.saturating_mul(1000) <= now_unix_ms)
+            .unwrap_or(false)
+    }
+
+    /// Dropped pings since the ban was added or the server started
+    pub fn hit_count(&self) -> u64 {
+        self.hits.count.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn record_hit(&self, now_unix_ms: u64) {
+        self.hits.count.fetch_add(1, Ordering::Relaxed);
+        self.hits
+            .last_hit_at_ms
+            .store(now_unix_ms, Ordering::Relaxed);
+    }
+
+    pub fn info(&self, now_unix_ms: u64) -> BanInfo {
+        let last_hit_at_ms = self.hits.last_hit_at_ms.load(Ordering::Relaxed);
+        BanInfo {
+            ban: self.clone(),
+            hits: self.hit_count(),
+            last_hit_at_ms: (last_hit_at_ms != 0).then_some(last_hit_at_ms),
+            expired: self.is_expired(now_unix_ms),
+        }
+    }
+}
+
+/// Bans grouped by prefix length (longest first), so finding the ban of
+/// a source takes one hash lookup per prefix length in use
+#[derive(Default)]
+pub struct BanTable {
+    by_prefix_len: Vec<(u8, HashMap<Ipv6Net, Ban>)>,
+}
+
+impl BanTable {
+    fn new(bans: &[Ban]) -> Self {
+        let mut by_prefix_len: Vec<(u8, HashMap<Ipv6Net, Ban>)> = Vec::new();
+        for ban in bans {
+            let prefix_len = ban.prefix.prefix_len();
+            let index = match by_prefix_len.iter().position(|(len, _)| *len == prefix_len) {
+                Some(index) => index,
+                None => {
+                    by_prefix_len.push((prefix_len, HashMap::new()));
+                    by_prefix_len.len() - 1
+                }
+            };
+            by_prefix_len[index]
+                .1
+                .insert(ban.prefix.trunc(), ban.clone());
+        }
+        by_prefix_len.sort_unstable_by_key(|(prefix_len, _)| std::cmp::Reverse(*prefix_len));
+        Self { by_prefix_len }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_prefix_len.is_empty()
+    }
+
+    /// The most specific ban of the source which didn't expire yet
+    #[inline]
+    pub fn find(&self, source: Ipv6Addr, now_unix_ms: u64) -> Option<&Ban> {
+        self.by_prefix_len.iter().find_map(|(prefix_len, bans)| {
+            let prefix = Ipv6Net::new(source, *prefix_len).ok()?.trunc();
+            bans.get(&prefix).filter(|ban| !ban.is_expired(now_unix_ms))
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Ban> {
+        self.by_prefix_len
+            .iter()
+            .flat_map(|(_, bans)| bans.values())
+    }
+}
+
+/// Replace the bans used by the ping listener. Call whenever the bans changed.
+pub fn publish(bans: &[Ban]) {
+    PUBLISHED_BANS.store(Some(Arc::new(BanTable::new(bans))));
+}
+
+/// The currently published bans
+pub fn published() -> Arc<BanTable> {
+    PUBLISHED_BANS.load_full().unwrap_or_default()
+}
+
+/// Called by the ping listener for each ping. Returns true (and counts the hit)
+/// if the source is banned.
+#[inline]
+pub fn record_if_banned(source: Ipv6Addr) -> bool {
+    let bans = PUBLISHED_BANS.load();
+    let Some(bans) = bans.as_ref().filter(|bans| !bans.is_empty()) else {
+        return false;
+    };
+    let now_unix_ms = unix_millis(SystemTime::now());
+    match bans.find(source, now_unix_ms) {
+        Some(ban) => {
+            ban.record_hit(now_unix_ms);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Normalize the prefixes, merge duplicates (last one wins) and keep the
+/// hit counters of bans which already existed
+pub fn prepare_all(bans: &mut Vec<Ban>, existing: &[Ban]) {
+    for ban in bans.iter_mut() {
+        ban.prefix = ban.prefix.trunc();
+    }
+    let mut i = 0;
+    while i < bans.len() {
+        if bans[i + 1..]
+            .iter()
+            .any(|later| later.prefix == bans[i].prefix)
+        {
+            bans.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    for ban in bans.iter_mut() {
+        if let Some(old) = existing.iter().find(|old| old.prefix == ban.prefix) {
+            ban.hits = old.hits.clone();
+        }
+    }
+}
+
+pub fn load_from_file(path: &Path) -> Result<Vec<Ban>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Opening {path:?}"))?;
+    let mut bans: Vec<Ban> =
+        serde_json::from_reader(file).with_context(|| format!("Parsing {path:?}"))?;
+    prepare_all(&mut bans, &SERVER_CONFIG.lock().unwrap().bans);
+    Ok(bans)
+}
+
+fn save_to_file(path: &Path, bans: &[Ban]) -> Result<()> {
+    let json = serde_json::to_string_pretty(bans)?;
+    std::fs::write(path, json).with_context(|| format!("Writing {path:?}"))
+}
+
+/// Persist the current bans if they were loaded from a file. Blocks, but
+/// SERVER_CONFIG is only locked while copying the bans (not while writing).
+pub fn save() -> Result<()> {
+    let _saving = SAVE_LOCK.lock().unwrap();
+    let (bans, file) = {
+        let server_config = SERVER_CONFIG.lock().unwrap();
+        (server_config.bans.clone(), server_config.bans_file.clone())
+    };
+    let Some(file) = file else {
+        return Ok(());
+    };
+    save_to_file(&file, &bans)?;
+    *SAVED_MODIFIED_AT.lock().unwrap() = modified_at(&file);
+    Ok(())
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Reload the bans whenever the file was modified (except by our own saves)
+pub fn run_bans_file_watcher(path: PathBuf) -> Result<()> {
+    let mut last_modified_at = modified_at(&path);
+    loop {
+        std::thread::sleep(RELOAD_CHECK_INTERVAL);
+        let _saving = SAVE_LOCK.lock().unwrap();
+        let current_modified_at = modified_at(&path);
+        if current_modified_at == last_modified_at {
+            continue;
+        }
+        last_modified_at = current_modified_at;
+        if current_modified_at.is_some()
+            && current_modified_at == *SAVED_MODIFIED_AT.lock().unwrap()
+        {
+            // The bans in the file are the ones we just saved
+            continue;
+        }
+        match load_from_file(&path) {
+            Ok(bans) => {
+                info!("Reloaded {} bans from {path:?}", bans.len());
+                let mut server_config = SERVER_CONFIG.lock().unwrap();
+                publish(&bans);
+                server_config.bans = bans;
+            }
+            Err(err) => warn!("Keeping the current bans since reloading failed: {err:#}"),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Images the canvas starts with: An optional initial canvas (seeded once on
+//! startup) and an optional background layer which is visible wherever
+//! nobody painted yet (also after resets).
+//!
+//! The background layer is never drawn into the canvas itself (unpainted pixels
+//! stay white there), so stats and templates only see what was painted. It's
+//! only composited when the canvas gets encoded for clients or archived.
+
+use std::{borrow::Cow, path::Path};
+
+use color_eyre::{eyre::Context, Result};
+use image::{
+    imageops, imageops::FilterType, DynamicImage, GenericImageView, Rgb, RgbImage, Rgba, RgbaImage,
+};
+
+use crate::canvas::{CANVASH, CANVASW};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanvasFit {
+    /// Scale to cover the whole canvas (keeping the aspect ratio) and cut off what's left over
+    Resize,
+    /// Keep the original size and cut off everything right or below of the canvas
+    Crop,
+}
+
+/// Load an image and make it exactly as large as the canvas
+pub fn load_fitted(path: &Path, fit: CanvasFit) -> Result<RgbaImage> {
+    let image = image::open(path).with_context(|| format!("Loading image {path:?}"))?;
+    Ok(match fit {
+        CanvasFit::Resize => image
+            .resize_to_fill(CANVASW.into(), CANVASH.into(), FilterType::Lanczos3)
+            .to_rgba8(),
+        CanvasFit::Crop => {
+            let (width, height) = image.dimensions();
+            let mut fitted = RgbaImage::new(CANVASW.into(), CANVASH.into());
+            let cropped =
+                image.crop_imm(0, 0, width.min(CANVASW.into()), height.min(CANVASH.into()));
+            imageops::overlay(&mut fitted, &cropped.to_rgba8(), 0, 0);
+            fitted
+        }
+    })
+}
+
+/// What the canvas looks like if nobody painted on it (without the background layer)
+pub fn blank_canvas() -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_pixel(
+        CANVASW.into(),
+        CANVASH.into(),
+        Rgb([0xFF; 3]),
+    ))
+}
+
+/// The background layer and which pixels were painted over it
+pub struct Background {
+    /// Background layer drawn over white (None if there is none)
+    layer: Option<RgbImage>,
+    painted: Vec<bool>,
+}
+
+impl Background {
+    /// Nothing is painted yet
+    pub fn new(background_layer: Option<&RgbaImage>) -> Self {
+        let layer = background_layer.map(|background_layer| {
+            let mut layer = RgbaImage::from_pixel(CANVASW.into(), CANVASH.into(), Rgba([0xFF; 4]));
+            imageops::overlay(&mut layer, background_layer, 0, 0);
+            DynamicImage::ImageRgba8(layer).to_rgb8()
+        });
+        Self {
+            layer,
+            painted: vec![false; CANVASW as usize * CANVASH as usize],
+        }
+    }
+
+    #[inline]
+    fn index(x: u16, y: u16) -> usize {
+        y as usize * CANVASW as usize + x as usize
+    }
+
+    /// Mark a pixel as painted or not. Returns whether it was painted before.
+    #[inline]
+    pub fn set_painted(&mut self, x: u16, y: u16, painted: bool) -> bool {
+        std::mem::replace(&mut self.painted[Self::index(x, y)], painted)
+    }
+
+    /// Nothing is painted anymore (the canvas got reset)
+    pub fn clear(&mut self) {
+        self.painted.fill(false);
+    }
+
+    /// The color clients see for a pixel of the given color
+    #[inline]
+    pub fn shown_color(&self, x: u16, y: u16, color: Rgb<u8>, painted: bool) -> Rgb<u8> {
+        match &self.layer {
+            Some(layer) if !painted => *layer.get_pixel(x.into(), y.into()),
+            _ => color,
+        }
+    }
+
+    /// Draw the initial canvas over the blank canvas. Its pixels count as painted unless
+    /// they are fully transparent, so the background layer stays visible there.
+    pub fn seed_canvas(&mut self, initial_canvas: &RgbaImage) -> DynamicImage {
+        let mut seeded = RgbaImage::from_pixel(CANVASW.into(), CANVASH.into(), Rgba([0xFF; 4]));
+        imageops::overlay(&mut seeded, initial_canvas, 0, 0);
+        for (painted, pixel) in self.painted.iter_mut().zip(initial_canvas.pixels()) {
+            *painted = pixel.0[3] > 0;
+        }
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(seeded).to_rgb8())
+    }
+
+    /// Show the background layer wherever the canvas (or its top left part) wasn't painted
+    pub fn composite<'a>(&self, canvas: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        let Some(layer) = &self.layer else {
+            return Cow::Borrowed(canvas);
+        };
+        let mut composited = canvas.to_rgb8();
+        for (x, y, pixel) in composited.enumerate_pixels_mut() {
+            if !self.painted[Self::index(x as u16, y as u16)] {
+                *pixel = *layer.get_pixel(x, y);
+            }
+        }
+        Cow::Owned(DynamicImage::ImageRgb8(composited))
+    }
+}
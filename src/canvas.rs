@@ -1,27 +1,60 @@
 //! Canvas State struct and update/subscribe logic as well as encoding the canvas to a PNG binary.
 
 use std::{
+    collections::{HashMap, VecDeque},
     io::Cursor,
+    net::Ipv6Addr,
+    ops::{Deref, DerefMut},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::{Duration, SystemTime},
 };
 
 use color_eyre::{eyre::ensure, Result};
 use image::{codecs::png::PngEncoder, DynamicImage, GenericImageView, ImageEncoder};
+use ipnet::Ipv6Net;
 use serde::Serialize;
 use tokio::sync::{
     broadcast::{Receiver, Sender},
-    RwLock, RwLockReadGuard,
+    RwLock, RwLockReadGuard, RwLockWriteGuard,
 };
 
+use crate::admin_ws::AdminEvent;
+use crate::canvas_processor::ProcessorCommand;
+use crate::canvas_stats::CanvasStats;
+use crate::leaderboard::Leaderboard;
+use crate::pixel_events::PixelEventBatch;
+use crate::pixel_provenance::{unix_millis, PixelProvenance};
+use crate::pps_history::PpsHistory;
+use crate::scheduler::CanvasEvent;
+use crate::templates::{Template, TemplateStatus};
+use crate::viewport::ViewportCache;
+
 #[derive(Serialize, Clone)]
 pub struct PpsInfo {
     /// Total
     pub pps: usize,
     #[cfg(feature = "per_user_pps")]
     pub per_user_pps: fxhash::FxHashMap<u64, usize>,
+    /// Pixels per second dropped by the rate limit (only users with dropped pixels)
+    #[cfg(feature = "per_user_pps")]
+    #[serde(skip_serializing_if = "fxhash::FxHashMap::is_empty")]
+    pub per_user_dropped: fxhash::FxHashMap<u64, usize>,
+    /// Display names of the users in per_user_pps (only users which claimed one)
+    #[cfg(feature = "per_user_pps")]
+    #[serde(skip_serializing_if = "fxhash::FxHashMap::is_empty")]
+    pub per_user_names: fxhash::FxHashMap<u64, String>,
+}
+
+/// Sent when a user placed a pixel and has to wait for the cooldown to end
+#[derive(Copy, Clone)]
+pub struct CooldownEvent {
+    pub user_id: u64,
+    /// Prefix by which the user is tracked (never sent to clients)
+    pub user_prefix: Ipv6Net,
+    pub remaining: Duration,
 }
 
 #[derive(Copy, Clone)]
@@ -33,11 +66,37 @@ pub struct CanvasState {
     /// Base64 of png, starting with "data:image/png;base64," to denote this
     encoded_full_canvas: RwLock<EncodedCanvas>,
     encoded_delta_canvas: RwLock<EncodedCanvas>,
+    /// Colorized heatmap (RGBA)
+    encoded_heatmap: RwLock<EncodedCanvas>,
+    /// Only set while the canvas is considered nude and censoring is enabled
+    encoded_censored_full_canvas: RwLock<Option<Vec<u8>>>,
     pps_publisher: Sender<PpsInfo>,
+    pps_history: RwLock<PpsHistory>,
     ws_connection_count: Arc<AtomicUsize>,
     ws_connection_count_publisher: Sender<usize>,
     nudity_result: RwLock<NudityResult>,
     nudity_result_publisher: Sender<NudityResult>,
+    /// Cooldown events are only sent to the websockets of the user they're about
+    cooldown_watchers: Arc<Mutex<CooldownWatchers>>,
+    canvas_event_publisher: Sender<CanvasEvent>,
+    /// None until computed for the first time
+    stats: RwLock<Option<Arc<CanvasStats>>>,
+    stats_publisher: Sender<Arc<CanvasStats>>,
+    /// None until published for the first time
+    leaderboard: RwLock<Option<Arc<Leaderboard>>>,
+    leaderboard_publisher: Sender<Arc<Leaderboard>>,
+    templates: RwLock<Vec<Arc<Template>>>,
+    /// Result of the last check of all templates
+    template_statuses: RwLock<Arc<Vec<TemplateStatus>>>,
+    template_statuses_publisher: Sender<Arc<Vec<TemplateStatus>>>,
+    pixel_event_publisher: Sender<Arc<PixelEventBatch>>,
+    /// Canvas frames cropped to the viewports of websocket clients
+    viewport_cache: ViewportCache,
+    pixel_provenance: PixelProvenance,
+    /// Only sent to admins (contains source addresses)
+    admin_event_publisher: Sender<AdminEvent>,
+    processor_command_sender: crossbeam_channel::Sender<ProcessorCommand>,
+    processor_command_receiver: crossbeam_channel::Receiver<ProcessorCommand>,
 }
 
 impl CanvasState {
@@ -57,6 +116,52 @@ impl CanvasState {
         self.encoded_full_canvas.blocking_write().update(canvas)
     }
 
+    pub async fn read_encoded_heatmap(&self) -> RwLockReadGuard<'_, EncodedCanvas> {
+        self.encoded_heatmap.read().await
+    }
+
+    pub fn blocking_update_heatmap(&self, heatmap: &DynamicImage) -> Result<()> {
+        ensure!(
+            heatmap.as_rgba8().is_some(),
+            "Heatmap is expected to have an alpha layer!"
+        );
+        self.encoded_heatmap.blocking_write().update(heatmap)
+    }
+
+    /// Get the encoded full canvas. Returns the censored version instead if
+    /// `allow_censored` is true and the canvas is currently censored.
+    pub async fn get_encoded_full_canvas(&self, allow_censored: bool) -> Vec<u8> {
+        if allow_censored {
+            if let Some(encoded) = self.encoded_censored_full_canvas.read().await.as_ref() {
+                return encoded.clone();
+            }
+        }
+        self.read_encoded_full_canvas().await.get_encoded()
+    }
+
+    /// Whether the censored endpoints currently serve the censored canvas
+    pub async fn is_censored(&self) -> bool {
+        self.encoded_censored_full_canvas.read().await.is_some()
+    }
+
+    /// Only set while the canvas is censored
+    pub async fn encoded_censored_full_canvas(&self) -> Option<Vec<u8>> {
+        self.encoded_censored_full_canvas.read().await.clone()
+    }
+
+    /// Set or clear (None) the censored full canvas
+    pub fn blocking_update_censored_full_canvas(
+        &self,
+        canvas: Option<&DynamicImage>,
+    ) -> Result<()> {
+        let encoded = match canvas {
+            Some(canvas) => Some(EncodedCanvas::encode(canvas)?),
+            None => None,
+        };
+        *self.encoded_censored_full_canvas.blocking_write() = encoded;
+        Ok(())
+    }
+
     pub fn blocking_update_delta_canvas(&self, canvas: &DynamicImage) -> Result<()> {
         ensure!(
             canvas.as_rgba8().is_some(),
@@ -65,10 +170,16 @@ impl CanvasState {
         self.encoded_delta_canvas.blocking_write().update(canvas)
     }
 
-    pub fn update_pps(&self, pps: PpsInfo) {
+    /// Publish and remember the pps of the last second
+    pub fn blocking_update_pps(&self, pps: PpsInfo, now_ms: u64) {
+        self.pps_history.blocking_write().record(&pps, now_ms);
         self.pps_publisher.send(pps).ok();
     }
 
+    pub async fn pps_history(&self) -> RwLockReadGuard<'_, PpsHistory> {
+        self.pps_history.read().await
+    }
+
     pub fn subscribe_to_pps(&self) -> Receiver<PpsInfo> {
         self.pps_publisher.subscribe()
     }
@@ -96,6 +207,145 @@ impl CanvasState {
         self.nudity_result.read().await.clone()
     }
 
+    /// Send the event to the websockets of the user (if there are any which enabled cooldown updates)
+    #[cfg_attr(not(feature = "per_user_pps"), allow(unused))]
+    pub fn publish_cooldown_event(&self, cooldown_event: CooldownEvent) {
+        let watchers = self.cooldown_watchers.lock().unwrap();
+        if cooldown_event.user_prefix.prefix_len() >= 64 {
+            if let Some(publisher) = watchers.get(&cooldown_event.user_prefix.trunc()) {
+                publisher.send(cooldown_event).ok();
+            }
+        } else {
+            for (client_prefix, publisher) in watchers.iter() {
+                if cooldown_event.user_prefix.contains(client_prefix) {
+                    publisher.send(cooldown_event).ok();
+                }
+            }
+        }
+    }
+
+    /// Receive cooldown events of the user that the client address belongs to
+    pub fn subscribe_to_cooldown_events(&self, client_ip: Ipv6Addr) -> CooldownEventsReceiver {
+        let client_prefix = Ipv6Net::new(client_ip, 64).unwrap().trunc();
+        let receiver = self
+            .cooldown_watchers
+            .lock()
+            .unwrap()
+            .entry(client_prefix)
+            // Only events of one user, so a few are plenty
+            .or_insert_with(|| tokio::sync::broadcast::channel(16).0)
+            .subscribe();
+        CooldownEventsReceiver {
+            receiver,
+            client_prefix,
+            watchers: self.cooldown_watchers.clone(),
+        }
+    }
+
+    pub fn publish_canvas_event(&self, canvas_event: CanvasEvent) {
+        self.canvas_event_publisher.send(canvas_event).ok();
+    }
+
+    pub fn subscribe_to_canvas_events(&self) -> Receiver<CanvasEvent> {
+        self.canvas_event_publisher.subscribe()
+    }
+
+    pub fn blocking_update_stats(&self, stats: CanvasStats) {
+        let stats = Arc::new(stats);
+        *self.stats.blocking_write() = Some(stats.clone());
+        self.stats_publisher.send(stats).ok();
+    }
+
+    pub async fn stats(&self) -> Option<Arc<CanvasStats>> {
+        self.stats.read().await.clone()
+    }
+
+    pub fn subscribe_to_stats(&self) -> Receiver<Arc<CanvasStats>> {
+        self.stats_publisher.subscribe()
+    }
+
+    pub fn blocking_update_leaderboard(&self, leaderboard: Leaderboard) {
+        let leaderboard = Arc::new(leaderboard);
+        *self.leaderboard.blocking_write() = Some(leaderboard.clone());
+        self.leaderboard_publisher.send(leaderboard).ok();
+    }
+
+    pub async fn leaderboard(&self) -> Option<Arc<Leaderboard>> {
+        self.leaderboard.read().await.clone()
+    }
+
+    pub fn subscribe_to_leaderboard(&self) -> Receiver<Arc<Leaderboard>> {
+        self.leaderboard_publisher.subscribe()
+    }
+
+    pub async fn templates(&self) -> Vec<Arc<Template>> {
+        self.templates.read().await.clone()
+    }
+
+    pub async fn write_templates(&self) -> RwLockWriteGuard<'_, Vec<Arc<Template>>> {
+        self.templates.write().await
+    }
+
+    pub fn blocking_templates(&self) -> Vec<Arc<Template>> {
+        self.templates.blocking_read().clone()
+    }
+
+    pub async fn template_statuses(&self) -> Arc<Vec<TemplateStatus>> {
+        self.template_statuses.read().await.clone()
+    }
+
+    pub fn blocking_update_template_statuses(&self, statuses: Vec<TemplateStatus>) {
+        let statuses = Arc::new(statuses);
+        *self.template_statuses.blocking_write() = statuses.clone();
+        self.template_statuses_publisher.send(statuses).ok();
+    }
+
+    pub fn subscribe_to_template_statuses(&self) -> Receiver<Arc<Vec<TemplateStatus>>> {
+        self.template_statuses_publisher.subscribe()
+    }
+
+    pub fn publish_pixel_events(&self, batch: PixelEventBatch) {
+        self.pixel_event_publisher.send(Arc::new(batch)).ok();
+    }
+
+    pub fn subscribe_to_pixel_events(&self) -> Receiver<Arc<PixelEventBatch>> {
+        self.pixel_event_publisher.subscribe()
+    }
+
+    /// Pixel events don't need to be collected while nobody wants them
+    pub fn has_pixel_event_subscribers(&self) -> bool {
+        self.pixel_event_publisher.receiver_count() > 0
+    }
+
+    pub fn viewport_cache(&self) -> &ViewportCache {
+        &self.viewport_cache
+    }
+
+    pub fn pixel_provenance(&self) -> &PixelProvenance {
+        &self.pixel_provenance
+    }
+
+    pub fn publish_admin_event(&self, admin_event: AdminEvent) {
+        self.admin_event_publisher.send(admin_event).ok();
+    }
+
+    pub fn subscribe_to_admin_events(&self) -> Receiver<AdminEvent> {
+        self.admin_event_publisher.subscribe()
+    }
+
+    /// Admin events don't need to be collected while no admin is connected
+    pub fn has_admin_subscribers(&self) -> bool {
+        self.admin_event_publisher.receiver_count() > 0
+    }
+
+    pub fn send_processor_command(&self, command: ProcessorCommand) {
+        self.processor_command_sender.send(command).ok();
+    }
+
+    pub fn processor_commands(&self) -> &crossbeam_channel::Receiver<ProcessorCommand> {
+        &self.processor_command_receiver
+    }
+
     pub fn blocking_update_nudity_result(&self, new_nudity_result: NudityResult) {
         *self.nudity_result.blocking_write() = new_nudity_result;
         self.nudity_result_publisher.send(new_nudity_result).ok();
@@ -107,20 +357,45 @@ pub(crate) const CANVASH: u16 = 512;
 
 impl Default for CanvasState {
     fn default() -> Self {
+        let (processor_command_sender, processor_command_receiver) = crossbeam_channel::unbounded();
         Self {
             encoded_full_canvas: RwLock::new(
                 EncodedCanvas::new(&DynamicImage::new_rgb8(CANVASW.into(), CANVASH.into()))
                     .unwrap(),
             ),
             encoded_delta_canvas: RwLock::new(
+                EncodedCanvas::with_backlog(
+                    &DynamicImage::new_rgba8(CANVASW.into(), CANVASH.into()),
+                    DELTA_BACKLOG_FRAMES,
+                )
+                .unwrap(),
+            ),
+            encoded_heatmap: RwLock::new(
                 EncodedCanvas::new(&DynamicImage::new_rgba8(CANVASW.into(), CANVASH.into()))
                     .unwrap(),
             ),
+            encoded_censored_full_canvas: RwLock::new(None),
             pps_publisher: tokio::sync::broadcast::channel(64).0,
+            pps_history: RwLock::new(PpsHistory::default()),
             ws_connection_count: Arc::new(AtomicUsize::new(0)),
             ws_connection_count_publisher: tokio::sync::broadcast::channel(64).0,
             nudity_result: RwLock::new(NudityResult { is_nude: false }),
             nudity_result_publisher: tokio::sync::broadcast::channel(64).0,
+            cooldown_watchers: Arc::new(Mutex::new(HashMap::new())),
+            canvas_event_publisher: tokio::sync::broadcast::channel(64).0,
+            stats: RwLock::new(None),
+            stats_publisher: tokio::sync::broadcast::channel(16).0,
+            leaderboard: RwLock::new(None),
+            leaderboard_publisher: tokio::sync::broadcast::channel(16).0,
+            templates: RwLock::new(Vec::new()),
+            template_statuses: RwLock::new(Arc::new(Vec::new())),
+            template_statuses_publisher: tokio::sync::broadcast::channel(16).0,
+            pixel_event_publisher: tokio::sync::broadcast::channel(64).0,
+            viewport_cache: ViewportCache::default(),
+            pixel_provenance: PixelProvenance::default(),
+            admin_event_publisher: tokio::sync::broadcast::channel(256).0,
+            processor_command_sender,
+            processor_command_receiver,
         }
     }
 }
@@ -146,17 +421,73 @@ impl Drop for WsConnectionCountTracker {
     }
 }
 
+/// Publisher per /64 of websocket clients which enabled cooldown updates
+type CooldownWatchers = HashMap<Ipv6Net, Sender<CooldownEvent>>;
+
+/// Stops sending cooldown events for the /64 of the client when the last receiver of it is dropped
+pub struct CooldownEventsReceiver {
+    receiver: Receiver<CooldownEvent>,
+    client_prefix: Ipv6Net,
+    watchers: Arc<Mutex<CooldownWatchers>>,
+}
+
+impl Deref for CooldownEventsReceiver {
+    type Target = Receiver<CooldownEvent>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
+impl DerefMut for CooldownEventsReceiver {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.receiver
+    }
+}
+
+impl Drop for CooldownEventsReceiver {
+    fn drop(&mut self) {
+        let mut watchers = self.watchers.lock().unwrap();
+        // This receiver is still alive, so it's the last one if the count is 1
+        if let Some(publisher) = watchers.get(&self.client_prefix) {
+            if publisher.receiver_count() <= 1 {
+                watchers.remove(&self.client_prefix);
+            }
+        }
+    }
+}
+
+/// How many delta frames are kept to let reconnecting clients resume (a minute at 10 fps)
+const DELTA_BACKLOG_FRAMES: usize = 600;
+/// Older delta frames are dropped from the backlog if it gets larger than this
+const DELTA_BACKLOG_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// An encoded canvas (png) tagged with when it was produced
+pub struct EncodedFrame {
+    /// Increases by one with each update. Starts at the unix time in milliseconds
+    /// of the initial canvas, so numbers of a restarted server don't overlap
+    /// with earlier ones (there is at most one update per millisecond).
+    pub seq: u64,
+    /// Unix time in milliseconds
+    pub at_ms: u64,
+    pub png: Vec<u8>,
+}
+
 #[derive(Clone)]
 pub struct EncodedCanvas {
-    encoded: Vec<u8>,
-    publisher: Sender<Vec<u8>>,
+    current: Arc<EncodedFrame>,
+    /// Most recent frames, oldest first (empty if created without a backlog)
+    backlog: VecDeque<Arc<EncodedFrame>>,
+    backlog_frames: usize,
+    backlog_bytes: usize,
+    publisher: Sender<Arc<EncodedFrame>>,
 }
 
 impl EncodedCanvas {
-    fn encode(canvas: &DynamicImage) -> Result<Vec<u8>> {
+    pub fn encode(canvas: &DynamicImage) -> Result<Vec<u8>> {
         ensure!(
-            canvas.width() == CANVASW as u32 && canvas.height() == CANVASH as u32,
-            "Canvas has correct dimensions"
+            canvas.width() <= CANVASW as u32 && canvas.height() <= CANVASH as u32,
+            "Canvas is not larger than the maximum size"
         );
 
         // Encode as png into the writer
@@ -172,24 +503,134 @@ impl EncodedCanvas {
     }
 
     pub fn new(canvas: &DynamicImage) -> Result<Self> {
+        Self::with_backlog(canvas, 0)
+    }
+
+    /// Keep the last `backlog_frames` frames (see frames_since)
+    pub fn with_backlog(canvas: &DynamicImage, backlog_frames: usize) -> Result<Self> {
+        let now_ms = unix_millis(SystemTime::now());
         Ok(Self {
-            encoded: Self::encode(canvas)?,
+            current: Arc::new(EncodedFrame {
+                seq: now_ms,
+                at_ms: now_ms,
+                png: Self::encode(canvas)?,
+            }),
+            backlog: VecDeque::with_capacity(backlog_frames),
+            backlog_frames,
+            backlog_bytes: 0,
             publisher: tokio::sync::broadcast::channel(64).0,
         })
     }
 
     pub fn update(&mut self, canvas: &DynamicImage) -> Result<()> {
-        let encoded = Self::encode(canvas)?;
-        self.encoded = encoded.clone();
-        self.publisher.send(encoded).ok();
+        let frame = Arc::new(EncodedFrame {
+            seq: self.current.seq + 1,
+            at_ms: unix_millis(SystemTime::now()),
+            png: Self::encode(canvas)?,
+        });
+        self.current = frame.clone();
+        if self.backlog_frames > 0 {
+            self.backlog_bytes += frame.png.len();
+            self.backlog.push_back(frame.clone());
+            while self.backlog.len() > self.backlog_frames
+                || self.backlog_bytes > DELTA_BACKLOG_MAX_BYTES
+            {
+                let Some(dropped) = self.backlog.pop_front() else {
+                    break;
+                };
+                self.backlog_bytes -= dropped.png.len();
+            }
+        }
+        self.publisher.send(frame).ok();
         Ok(())
     }
 
-    pub fn subscribe(&self) -> Receiver<Vec<u8>> {
+    pub fn subscribe(&self) -> Receiver<Arc<EncodedFrame>> {
         self.publisher.subscribe()
     }
 
     pub fn get_encoded(&self) -> Vec<u8> {
-        self.encoded.clone()
+        self.current.png.clone()
+    }
+
+    pub fn current(&self) -> Arc<EncodedFrame> {
+        self.current.clone()
+    }
+
+    /// All frames after `seq` (oldest first).
+    /// None if some of them aren't in the backlog anymore (or `seq` is unknown).
+    pub fn frames_since(&self, seq: u64) -> Option<Vec<Arc<EncodedFrame>>> {
+        if seq > self.current.seq {
+            return None;
+        }
+        if seq == self.current.seq {
+            return Some(Vec::new());
+        }
+        let oldest_seq = self.backlog.front()?.seq;
+        if seq + 1 < oldest_seq {
+            return None;
+        }
+        Some(
+            self.backlog
+                .iter()
+                .filter(|frame| frame.seq > seq)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqs(frames: Option<Vec<Arc<EncodedFrame>>>) -> Option<Vec<u64>> {
+        frames.map(|frames| frames.iter().map(|frame| frame.seq).collect())
+    }
+
+    #[test]
+    fn frames_since_returns_missed_frames() {
+        let image = DynamicImage::new_rgba8(4, 4);
+        let mut encoded = EncodedCanvas::with_backlog(&image, 10).unwrap();
+        let start = encoded.current().seq;
+        for _ in 0..3 {
+            encoded.update(&image).unwrap();
+        }
+        assert_eq!(encoded.current().seq, start + 3);
+        assert_eq!(
+            seqs(encoded.frames_since(start)),
+            Some(vec![start + 1, start + 2, start + 3])
+        );
+        assert_eq!(seqs(encoded.frames_since(start + 2)), Some(vec![start + 3]));
+        assert_eq!(seqs(encoded.frames_since(start + 3)), Some(vec![]));
+        // A seq from the future (e.g. of another server) is unknown
+        assert_eq!(seqs(encoded.frames_since(start + 4)), None);
+    }
+
+    #[test]
+    fn frames_since_detects_gaps_in_the_backlog() {
+        let image = DynamicImage::new_rgba8(4, 4);
+        let mut encoded = EncodedCanvas::with_backlog(&image, 2).unwrap();
+        let start = encoded.current().seq;
+        for _ in 0..4 {
+            encoded.update(&image).unwrap();
+        }
+        // Frames start + 1 and start + 2 were dropped
+        assert_eq!(seqs(encoded.frames_since(start)), None);
+        assert_eq!(seqs(encoded.frames_since(start + 1)), None);
+        assert_eq!(
+            seqs(encoded.frames_since(start + 2)),
+            Some(vec![start + 3, start + 4])
+        );
+    }
+
+    #[test]
+    fn frames_since_needs_a_backlog() {
+        let image = DynamicImage::new_rgba8(4, 4);
+        let mut encoded = EncodedCanvas::new(&image).unwrap();
+        let start = encoded.current().seq;
+        encoded.update(&image).unwrap();
+        assert_eq!(seqs(encoded.frames_since(start)), None);
+        assert_eq!(seqs(encoded.frames_since(start + 1)), Some(vec![]));
     }
 }
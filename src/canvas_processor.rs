@@ -3,19 +3,45 @@
 //! which update this state.
 //! Als sends updates in specified interval to all subscribers.
 
-use color_eyre::Result;
+use color_eyre::{eyre::Context, Result};
 use crossbeam_channel::Receiver;
-use image::{DynamicImage, Rgb, Rgba};
+use image::{DynamicImage, Rgb, Rgba, RgbaImage};
+use serde::Serialize;
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     net::Ipv6Addr,
+    path::{Path, PathBuf},
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
+use crate::admin_ws::{
+    AdminEvent, AdminPixel, AdminPixelBatch, AdminPpsInfo, BanHitRate, ModerationEvent,
+};
+use crate::base_layers::{self, Background};
 use crate::canvas::{NudityResult, CANVASW};
 use crate::canvas::{PpsInfo, CANVASH};
+use crate::canvas_stats::CanvasStatsTracker;
+use crate::censor::{censor_canvas, CensorStyle};
+use crate::heatmap::{Heatmap, HeatmapOptions};
+use crate::leaderboard::LeaderboardTracker;
+use crate::palette::Palette;
+use crate::pixel_events::{PixelEvent, PixelEventBatch};
+use crate::pixel_history::{PixelHistory, PixelWrite, RollbackTarget};
+use crate::pixel_provenance::{unix_millis, PixelWriter};
+use crate::scheduler::{EventPhase, ScheduledAction, ScheduledEvent, Scheduler};
 use crate::{canvas::CanvasState, ping_listener::IpInfo};
 
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GameMode {
+    /// Everyone can draw as fast as they can ping
+    FreeForAll,
+    /// Each user can only place one pixel per cooldown
+    Cooldown,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Size {
@@ -64,24 +90,164 @@ impl PixelInfo {
     }
 }
 
+/// Commands sent to the canvas processor by other threads (e.g. for admin requests)
+pub enum ProcessorCommand {
+    /// Revert all pixels written by target in the time range (unix time in milliseconds)
+    Rollback {
+        target: RollbackTarget,
+        from_ms: u64,
+        to_ms: u64,
+        reply: tokio::sync::oneshot::Sender<RollbackResult>,
+    },
+    /// Apply changed per user tracking thresholds
+    #[cfg(feature = "per_user_pps")]
+    SetTrackingOptions(crate::per_user_pps::TrackingOptions),
+}
+
+#[derive(Serialize)]
+pub struct RollbackResult {
+    pub restored_pixels: usize,
+    /// Unix time in milliseconds of the oldest write that can be rolled back
+    pub history_starts_at_ms: Option<u64>,
+}
+
+/// Draw a pixel on the canvas and delta canvas (as clients see it, with the background layer
+/// if not painted). Returns the previous color and whether the pixel was painted before.
+#[inline]
+fn put_pixel(
+    canvas: &mut DynamicImage,
+    delta_canvas: &mut DynamicImage,
+    background: &mut Background,
+    x: u16,
+    y: u16,
+    color: Rgb<u8>,
+    painted: bool,
+) -> (Rgb<u8>, bool) {
+    let canvas = canvas.as_mut_rgb8().unwrap();
+    let previous_color = *canvas.get_pixel(x as u32, y as u32);
+    canvas.put_pixel(x as u32, y as u32, color);
+    let previous_painted = background.set_painted(x, y, painted);
+    let shown = background.shown_color(x, y, color, painted);
+    delta_canvas.as_mut_rgba8().unwrap().put_pixel(
+        x as u32,
+        y as u32,
+        Rgba([shown.0[0], shown.0[1], shown.0[2], 0xFF]),
+    );
+    (previous_color, previous_painted)
+}
+
+/// Crop an image to the active area (only copies if that isn't the whole canvas)
+fn active_area(image: &DynamicImage, width: u16, height: u16) -> Cow<'_, DynamicImage> {
+    if width == CANVASW && height == CANVASH {
+        Cow::Borrowed(image)
+    } else {
+        Cow::Owned(image.crop_imm(0, 0, width.into(), height.into()))
+    }
+}
+
+/// Save the active area of the canvas as "canvas-<unix time>.png" in archive_dir
+fn archive_canvas(canvas: &DynamicImage, archive_dir: &Path, now_unix_ms: u64) -> Result<()> {
+    std::fs::create_dir_all(archive_dir).with_context(|| format!("Creating {archive_dir:?}"))?;
+    let path = archive_dir.join(format!("canvas-{}.png", now_unix_ms / 1000));
+    canvas
+        .save(&path)
+        .with_context(|| format!("Saving canvas to {path:?}"))?;
+    info!("Archived canvas to {path:?}");
+    Ok(())
+}
+
+/// Let frontends know about the current size, freeze and upcoming events
+fn update_server_config(scheduler: &Scheduler) {
+    let mut server_config = crate::SERVER_CONFIG.lock().unwrap();
+    (server_config.width, server_config.height) = scheduler.size();
+    server_config.frozen_until_ms = scheduler.frozen_until_ms();
+    server_config.scheduled_events = scheduler.announced_events();
+}
+
 /// Get adjusted PPS value which takes lag and other irregularities into account
 fn adjust_pps(elapsed_since_pps_counter_reset: Duration, pps_counter: usize) -> usize {
     ((pps_counter as u64 * 1_000_000) / elapsed_since_pps_counter_reset.as_micros() as u64) as usize
 }
 
+/// Settings of the canvas processor
+pub struct ProcessorOptions {
+    pub update_interval: Duration,
+    pub nudity_scan_interval: u16,
+    /// How to censor the canvas while nudity is detected (None to not censor)
+    pub censor_style: Option<CensorStyle>,
+    pub palette: Option<Palette>,
+    pub pixel_history_size: usize,
+    /// None disables the heatmap
+    pub heatmap: Option<HeatmapOptions>,
+    /// How often to refresh the canvas stats
+    pub stats_interval: Duration,
+    /// How often to publish the leaderboards
+    pub leaderboard_interval: Duration,
+    /// Max entries per leaderboard
+    pub leaderboard_size: usize,
+    /// How often to check the templates (if anything changed)
+    pub template_check_interval: Duration,
+    /// What the canvas looks like on startup (shown over the background layer)
+    pub initial_canvas: Option<RgbaImage>,
+    /// Visible wherever nobody painted yet (also after resets)
+    pub background_layer: Option<RgbaImage>,
+    pub schedule: Vec<ScheduledEvent>,
+    /// Where to save the canvas before resetting it
+    pub archive_dir: PathBuf,
+    #[cfg(feature = "per_user_pps")]
+    pub user_limits: crate::per_user_pps::UserLimits,
+    #[cfg(feature = "per_user_pps")]
+    pub tracking_options: crate::per_user_pps::TrackingOptions,
+}
+
 pub fn run_canvas_processor(
     pixel_receiver: Receiver<PixelInfo>,
     canvas_state: Arc<CanvasState>,
-    update_interval: Duration,
-    nudity_scan_interval: u16,
+    options: ProcessorOptions,
 ) -> Result<()> {
-    let mut canvas = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
-        CANVASW.into(),
-        CANVASH.into(),
-        Rgb([0xFF; 3]),
-    ));
-    canvas_state.blocking_update_full_canvas(&canvas)?;
+    let ProcessorOptions {
+        update_interval,
+        nudity_scan_interval,
+        censor_style,
+        palette,
+        pixel_history_size,
+        heatmap,
+        stats_interval,
+        leaderboard_interval,
+        leaderboard_size,
+        template_check_interval,
+        initial_canvas,
+        background_layer,
+        schedule,
+        archive_dir,
+        #[cfg(feature = "per_user_pps")]
+        user_limits,
+        #[cfg(feature = "per_user_pps")]
+        tracking_options,
+    } = options;
+
+    let blank_canvas = base_layers::blank_canvas();
+    let mut background = Background::new(background_layer.as_ref());
+    let mut canvas = match &initial_canvas {
+        Some(initial_canvas) => background.seed_canvas(initial_canvas),
+        None => blank_canvas.clone(),
+    };
+    let mut scheduler = Scheduler::new(schedule, unix_millis(SystemTime::now()));
+    let (mut active_width, mut active_height) = scheduler.size();
+    update_server_config(&scheduler);
+    canvas_state.blocking_update_full_canvas(&background.composite(&active_area(
+        &canvas,
+        active_width,
+        active_height,
+    )))?;
     let mut delta_canvas = DynamicImage::new_rgba8(CANVASW.into(), CANVASH.into());
+    let mut pixel_history = PixelHistory::new(pixel_history_size);
+    let mut heatmap = heatmap.map(|options| Heatmap::new(options, Instant::now()));
+    let mut stats = CanvasStatsTracker::new(
+        &active_area(&canvas, active_width, active_height),
+        stats_interval,
+    );
+    let mut leaderboard = LeaderboardTracker::new(leaderboard_size, leaderboard_interval);
 
     let (nudity_image_sender, nudity_image_receiver) = crossbeam_channel::bounded(1);
     if nudity_scan_interval > 0 {
@@ -90,41 +256,123 @@ pub fn run_canvas_processor(
         std::thread::Builder::new()
             .name("Nudity-Checker".to_owned())
             .spawn(move || {
-                if let Err(err) = run_nudity_checker(nudity_image_receiver, canvas_state_clone) {
+                if let Err(err) =
+                    run_nudity_checker(nudity_image_receiver, canvas_state_clone, censor_style)
+                {
                     error!("Nudity-Checker crashed: {err:#}");
                 }
             })?;
     }
 
+    let (template_canvas_sender, template_canvas_receiver) = crossbeam_channel::bounded(1);
+    {
+        // Comparing the templates takes a while, so it gets its own thread as well
+        let canvas_state_clone = canvas_state.clone();
+        std::thread::Builder::new()
+            .name("Template-Checker".to_owned())
+            .spawn(move || {
+                if let Err(err) = crate::templates::run_template_checker(
+                    template_canvas_receiver,
+                    canvas_state_clone,
+                    template_check_interval,
+                ) {
+                    error!("Template-Checker crashed: {err:#}");
+                }
+            })?;
+    }
+    let mut template_canvas_changed = true;
+    let mut template_canvas_sent_at: Option<Instant> = None;
+
     info!("Started. Listening for Pixel updates to update and encode canvas...");
 
     let mut pending_update = false;
 
     let mut pps_counter_reset_at = Instant::now();
     let mut pps_counter: usize = 0;
+    // Hits of each ban at the last pps update (bans are checked by the ping listener)
+    let mut ban_hit_counts: HashMap<ipnet::Ipv6Net, u64> = HashMap::new();
     #[cfg(feature = "per_user_pps")]
-    let mut per_user_pps_last_cleaned = Instant::now();
+    let mut pps_users = crate::per_user_pps::PpsUsers::new(tracking_options, Instant::now());
 
     let mut nudity_interval_counter: u64 = 0;
     let mut nudity_image_changed_since_last_scan = false;
     for tick in crossbeam_channel::tick(update_interval) {
         let now = tick;
+        let now_unix_ms = unix_millis(SystemTime::now());
+        let admin_watching = canvas_state.has_admin_subscribers();
+        let pixel_events_watched = canvas_state.has_pixel_event_subscribers();
+        let protected_regions = crate::protected_regions::published();
+
+        let canvas_events = scheduler.poll(now_unix_ms);
+        for canvas_event in &canvas_events {
+            if canvas_event.phase == EventPhase::Started {
+                if let ScheduledAction::Reset = canvas_event.event.action {
+                    let archived = archive_canvas(
+                        &background.composite(&active_area(&canvas, active_width, active_height)),
+                        &archive_dir,
+                        now_unix_ms,
+                    );
+                    if let Err(err) = archived {
+                        // A missing archive is less bad than a reset which doesn't happen as announced
+                        warn!("Resetting the canvas even though archiving it failed: {err:#}");
+                        canvas_state.publish_admin_event(AdminEvent::Moderation(Arc::new(
+                            ModerationEvent::ArchiveFailed {
+                                error: format!("{err:#}"),
+                            },
+                        )));
+                    }
+                    canvas = blank_canvas.clone();
+                    background.clear();
+                    delta_canvas =
+                        DynamicImage::ImageRgba8(background.composite(&canvas).to_rgba8());
+                    canvas_state.pixel_provenance().clear();
+                    stats.clear_activity();
+                    pixel_history.clear();
+                    leaderboard.clear_survived();
+                    pending_update = true;
+                }
+            }
+            info!("Scheduled event: {canvas_event:?}");
+            canvas_state.publish_canvas_event(*canvas_event);
+        }
+        if !canvas_events.is_empty() {
+            update_server_config(&scheduler);
+            if scheduler.size() != (active_width, active_height) {
+                let (old_width, old_height) = (active_width, active_height);
+                (active_width, active_height) = scheduler.size();
+                info!("Canvas was resized to {active_width}x{active_height}");
+                if active_width < old_width || active_height < old_height {
+                    // Shrinking must not leave pixels behind which show up again when growing later
+                    let blank = blank_canvas.as_rgb8().unwrap();
+                    for y in 0..old_height {
+                        for x in 0..old_width {
+                            if x < active_width && y < active_height {
+                                continue;
+                            }
+                            canvas.as_mut_rgb8().unwrap().put_pixel(
+                                x.into(),
+                                y.into(),
+                                *blank.get_pixel(x.into(), y.into()),
+                            );
+                            background.set_painted(x, y, false);
+                            let previous_writer =
+                                canvas_state
+                                    .pixel_provenance()
+                                    .record(x, y, PixelWriter::NONE);
+                            leaderboard.record_owner_change(None, previous_writer.user_id);
+                        }
+                    }
+                    pixel_history.retain_inside(active_width, active_height);
+                }
+                pending_update = true;
+            }
+            if pending_update {
+                stats.recount(&active_area(&canvas, active_width, active_height));
+            }
+        }
 
         #[cfg(feature = "per_user_pps")]
-        let (mut pps_users, mut pps_next_user_id, pps_per_user_is_disabled) = {
-            let mut pps_users = crate::per_user_pps::PPS_USERS.lock().unwrap();
-            if now - per_user_pps_last_cleaned > Duration::from_secs(60) {
-                crate::per_user_pps::cleanup(&mut pps_users, now);
-                per_user_pps_last_cleaned = now;
-            }
-            let pps_next_user_id = crate::per_user_pps::PPS_NEXT_USER_ID.lock().unwrap();
-            let mut pps_disabled_until = crate::per_user_pps::PPS_USERS_DISABLED_UNTIIL
-                .lock()
-                .unwrap();
-            let pps_per_user_is_disabled =
-                crate::per_user_pps::is_disabled(&mut pps_users, &mut pps_disabled_until, now);
-            (pps_users, pps_next_user_id, pps_per_user_is_disabled)
-        };
+        crate::per_user_pps::cleanup_if_due(&mut pps_users, now);
 
         let elapsed_since_pps_counter_reset = now - pps_counter_reset_at;
         if elapsed_since_pps_counter_reset >= Duration::from_secs(1) {
@@ -132,75 +380,386 @@ pub fn run_canvas_processor(
             let pps_adjusted = adjust_pps(elapsed_since_pps_counter_reset, pps_counter);
             pps_counter_reset_at = now;
             #[cfg(feature = "per_user_pps")]
-            let per_user_pps = {
+            let mut per_prefix = Vec::new();
+            #[cfg(feature = "per_user_pps")]
+            let (per_user_pps, per_user_dropped) = {
                 let map = crate::per_user_pps::get_all_pps_counters_and_reset(&mut pps_users);
                 let mut per_user_pps = fxhash::FxHashMap::with_capacity_and_hasher(
                     map.len(),
                     fxhash::FxBuildHasher::default(),
                 );
-                for (user, pps) in map {
-                    per_user_pps.insert(user.id, adjust_pps(elapsed_since_pps_counter_reset, pps));
+                let mut per_user_dropped = fxhash::FxHashMap::default();
+                for (user, counters) in map {
+                    let pps = adjust_pps(elapsed_since_pps_counter_reset, counters.pps);
+                    let dropped = adjust_pps(elapsed_since_pps_counter_reset, counters.dropped);
+                    per_user_pps.insert(user.id, pps);
+                    if dropped > 0 {
+                        per_user_dropped.insert(user.id, dropped);
+                    }
+                    if admin_watching {
+                        per_prefix.push(crate::admin_ws::PrefixPps {
+                            prefix: counters.prefix,
+                            user_id: user.id,
+                            pps,
+                            dropped,
+                        });
+                    }
+                }
+                (per_user_pps, per_user_dropped)
+            };
+            #[cfg(feature = "per_user_pps")]
+            let untracked = {
+                let counters =
+                    crate::per_user_pps::get_untracked_pps_counters_and_reset(&mut pps_users);
+                crate::admin_ws::UntrackedPps {
+                    pps: adjust_pps(elapsed_since_pps_counter_reset, counters.pps),
+                    dropped: adjust_pps(elapsed_since_pps_counter_reset, counters.dropped),
                 }
+            };
+            #[cfg(feature = "per_user_pps")]
+            let per_user_names = {
+                let display_names = crate::display_names::DISPLAY_NAMES.lock().unwrap();
                 per_user_pps
+                    .keys()
+                    .filter_map(|user_id| Some((*user_id, display_names.get(*user_id)?.to_owned())))
+                    .collect()
             };
             let pps_info = PpsInfo {
                 pps: pps_adjusted,
                 #[cfg(feature = "per_user_pps")]
                 per_user_pps,
+                #[cfg(feature = "per_user_pps")]
+                per_user_dropped,
+                #[cfg(feature = "per_user_pps")]
+                per_user_names,
             };
-            canvas_state.update_pps(pps_info);
+            canvas_state.blocking_update_pps(pps_info, now_unix_ms);
+            let previous_ban_hit_counts = std::mem::take(&mut ban_hit_counts);
+            let mut ban_hits = Vec::new();
+            for ban in crate::bans::published().iter() {
+                let hits = ban.hit_count();
+                ban_hit_counts.insert(ban.prefix, hits);
+                // Saturating, since a ban which was removed and added again starts at 0
+                let new_hits =
+                    hits.saturating_sub(*previous_ban_hit_counts.get(&ban.prefix).unwrap_or(&hits));
+                if new_hits > 0 {
+                    ban_hits.push(BanHitRate {
+                        prefix: ban.prefix,
+                        pps: adjust_pps(elapsed_since_pps_counter_reset, new_hits as usize),
+                    });
+                }
+            }
+            if admin_watching {
+                canvas_state.publish_admin_event(AdminEvent::PpsUpdate(Arc::new(AdminPpsInfo {
+                    pps: pps_adjusted,
+                    #[cfg(feature = "per_user_pps")]
+                    per_prefix,
+                    #[cfg(feature = "per_user_pps")]
+                    untracked,
+                    ban_hits,
+                    listener: crate::ping_listener::LISTENER_COUNTERS.snapshot(),
+                })));
+            }
             pps_counter = 0;
         }
 
-        for pixel_info in pixel_receiver.try_iter() {
+        let is_frozen = scheduler.is_frozen();
+        let mut admin_pixels = AdminPixelBatch::default();
+        let mut pixel_events = PixelEventBatch {
+            at_ms: now_unix_ms,
+            ..Default::default()
+        };
+        // Pixels of the current ping which were actually drawn (not protected or outside of the active area)
+        let mut written_pixels: Vec<(u16, u16)> = Vec::new();
+        for mut pixel_info in pixel_receiver.try_iter() {
             pps_counter += 1;
+            if is_frozen {
+                continue;
+            }
+            if let Some(palette) = &palette {
+                match palette.apply(pixel_info.color) {
+                    Some(color) => pixel_info.color = color,
+                    None => continue,
+                }
+            }
+            #[cfg_attr(not(feature = "per_user_pps"), allow(unused_mut))]
+            let mut user_id = None;
             #[cfg(feature = "per_user_pps")]
             {
-                if !pps_per_user_is_disabled {
-                    crate::per_user_pps::ensure_existing_activity_updated_and_migrated(
+                crate::per_user_pps::ensure_existing_activity_updated_and_migrated(
+                    &mut pps_users,
+                    now,
+                    pixel_info.source,
+                );
+                // Sources which aren't tracked share one rate limit and cooldown
+                let (is_tracked, user_info) =
+                    crate::per_user_pps::find_user_info_data_or_untracked_mut(
                         &mut pps_users,
-                        &mut pps_next_user_id,
-                        now,
                         pixel_info.source,
                     );
-                    if let Some(user_info) = crate::per_user_pps::find_user_info_data_mut(
-                        &mut pps_users,
-                        pixel_info.source,
-                    ) {
-                        user_info.pps_counter += 1;
+                if is_tracked {
+                    user_id = Some(user_info.get_user_id().id);
+                }
+                user_info.pps_counter += 1;
+                if let Some(rate_limit) = &user_limits.rate_limit {
+                    if !user_info.try_take_rate_limit_token(rate_limit, now) {
+                        user_info.dropped_counter += 1;
+                        continue;
                     }
                 }
+                if user_limits.pixel_cooldown.is_some()
+                    && user_info.cooldown_remaining(now).is_some()
+                {
+                    user_info.dropped_counter += 1;
+                    continue;
+                }
             }
 
+            written_pixels.clear();
             for x_offset in 0..(pixel_info.size as u16) {
                 let x = pixel_info.pos.x + x_offset;
-                if x >= CANVASW {
+                if x >= active_width {
                     break;
                 }
                 for y_offset in 0..(pixel_info.size as u16) {
                     let y = pixel_info.pos.y + y_offset;
-                    if y >= CANVASH {
+                    if y >= active_height {
                         break;
                     }
+                    if protected_regions
+                        .iter()
+                        .any(|region| region.protects(x, y, pixel_info.source))
+                    {
+                        continue;
+                    }
 
-                    canvas
-                        .as_mut_rgb8()
-                        .unwrap()
-                        .put_pixel(x as u32, y as u32, pixel_info.color);
-                    delta_canvas.as_mut_rgba8().unwrap().put_pixel(
-                        x as u32,
-                        y as u32,
-                        Rgba([
-                            pixel_info.color.0[0],
-                            pixel_info.color.0[1],
-                            pixel_info.color.0[2],
-                            0xFF,
-                        ]),
+                    let (previous_color, previous_painted) = put_pixel(
+                        &mut canvas,
+                        &mut delta_canvas,
+                        &mut background,
+                        x,
+                        y,
+                        pixel_info.color,
+                        true,
+                    );
+                    let writer = PixelWriter {
+                        user_id,
+                        written_at_ms: now_unix_ms,
+                    };
+                    let previous_writer = canvas_state.pixel_provenance().record(x, y, writer);
+                    stats.record_write(
+                        previous_color,
+                        pixel_info.color,
+                        previous_writer.written_at_ms,
+                        now_unix_ms,
                     );
+                    leaderboard.record_write(user_id, previous_writer.user_id, now_unix_ms);
+                    pixel_history.record(PixelWrite {
+                        x,
+                        y,
+                        source: Some(pixel_info.source),
+                        writer,
+                        previous_color,
+                        previous_writer,
+                        previous_painted,
+                    });
+                    if let Some(heatmap) = &mut heatmap {
+                        heatmap.record_write(x, y);
+                    }
+                    written_pixels.push((x, y));
+                }
+            }
+            if written_pixels.is_empty() {
+                // Nothing was drawn, so the user isn't charged for it
+                continue;
+            }
+
+            #[cfg(feature = "per_user_pps")]
+            if let Some(pixel_cooldown) = user_limits.pixel_cooldown {
+                let (_, user_info) = crate::per_user_pps::find_user_info_data_or_untracked_mut(
+                    &mut pps_users,
+                    pixel_info.source,
+                );
+                user_info.start_cooldown(pixel_cooldown, now);
+                pps_users.mark_changed();
+                if let Some(cooldown_event) =
+                    crate::per_user_pps::cooldown_event(&pps_users, pixel_info.source, now)
+                {
+                    canvas_state.publish_cooldown_event(cooldown_event);
+                }
+            }
+            if let Some(user_id) = user_id {
+                stats.record_user(user_id, now_unix_ms);
+            }
+
+            let size = pixel_info.size as usize;
+            let fully_drawn = written_pixels.len() == size * size;
+            if admin_watching {
+                if fully_drawn {
+                    admin_pixels.push(AdminPixel {
+                        source: pixel_info.source,
+                        user_id,
+                        x: pixel_info.pos.x,
+                        y: pixel_info.pos.y,
+                        size: pixel_info.size as u8,
+                        color: pixel_info.color,
+                    });
+                } else {
+                    // Only partly drawn, so each drawn pixel gets its own entry
+                    for &(x, y) in &written_pixels {
+                        admin_pixels.push(AdminPixel {
+                            source: pixel_info.source,
+                            user_id,
+                            x,
+                            y,
+                            size: 1,
+                            color: pixel_info.color,
+                        });
+                    }
+                }
+            }
+            if pixel_events_watched {
+                if fully_drawn {
+                    pixel_events.push(PixelEvent {
+                        x: pixel_info.pos.x,
+                        y: pixel_info.pos.y,
+                        size: pixel_info.size as u8,
+                        color: pixel_info.color,
+                        user_id,
+                    });
+                } else {
+                    // Only partly drawn, so each drawn pixel gets its own event
+                    for &(x, y) in &written_pixels {
+                        pixel_events.push(PixelEvent {
+                            x,
+                            y,
+                            size: 1,
+                            color: pixel_info.color,
+                            user_id,
+                        });
+                    }
                 }
             }
             pending_update = true;
         }
+        if !admin_pixels.pixels.is_empty() {
+            canvas_state.publish_admin_event(AdminEvent::Pixels(Arc::new(admin_pixels)));
+        }
+        if !pixel_events.events.is_empty() {
+            canvas_state.publish_pixel_events(pixel_events);
+        }
+
+        #[cfg(feature = "per_user_pps")]
+        pps_users.publish_snapshot_if_due(now);
+
+        for command in canvas_state.processor_commands().try_iter() {
+            match command {
+                ProcessorCommand::Rollback {
+                    target,
+                    from_ms,
+                    to_ms,
+                    reply,
+                } => {
+                    let restored_pixels = pixel_history.rollback(target, from_ms, to_ms);
+                    for restored in &restored_pixels {
+                        let (previous_color, previous_painted) = put_pixel(
+                            &mut canvas,
+                            &mut delta_canvas,
+                            &mut background,
+                            restored.x,
+                            restored.y,
+                            restored.color,
+                            restored.painted,
+                        );
+                        let previous_writer = canvas_state.pixel_provenance().record(
+                            restored.x,
+                            restored.y,
+                            restored.writer,
+                        );
+                        stats.record_write(
+                            previous_color,
+                            restored.color,
+                            previous_writer.written_at_ms,
+                            restored.writer.written_at_ms,
+                        );
+                        leaderboard
+                            .record_owner_change(restored.writer.user_id, previous_writer.user_id);
+                        pixel_history.record(PixelWrite {
+                            x: restored.x,
+                            y: restored.y,
+                            source: None,
+                            writer: PixelWriter {
+                                user_id: None,
+                                written_at_ms: now_unix_ms,
+                            },
+                            previous_color,
+                            previous_writer,
+                            previous_painted,
+                        });
+                    }
+                    info!(
+                        "Rolled back {} pixels of {target:?} written between {from_ms} and {to_ms}",
+                        restored_pixels.len()
+                    );
+                    if !restored_pixels.is_empty() {
+                        pending_update = true;
+                    }
+                    reply
+                        .send(RollbackResult {
+                            restored_pixels: restored_pixels.len(),
+                            history_starts_at_ms: pixel_history.oldest_write_ms(),
+                        })
+                        .ok();
+                }
+                #[cfg(feature = "per_user_pps")]
+                ProcessorCommand::SetTrackingOptions(tracking_options) => {
+                    pps_users.set_options(tracking_options);
+                }
+            }
+        }
+
+        if let Some(heatmap) = &mut heatmap {
+            heatmap.decay(now);
+            if let Some(rendered) = heatmap.render_if_due(now) {
+                canvas_state.blocking_update_heatmap(&active_area(
+                    &rendered,
+                    active_width,
+                    active_height,
+                ))?;
+            }
+        }
+
+        if let Some(stats) = stats.refresh_if_due(
+            now,
+            now_unix_ms,
+            &active_area(&canvas, active_width, active_height),
+        ) {
+            canvas_state.blocking_update_stats(stats);
+        }
+
+        if pending_update {
+            template_canvas_changed = true;
+        }
+        if template_canvas_changed
+            && template_canvas_sent_at
+                .map(|sent_at| now.saturating_duration_since(sent_at) >= template_check_interval)
+                .unwrap_or(true)
+            && template_canvas_sender.try_send(canvas.clone()).is_ok()
+        {
+            template_canvas_changed = false;
+            template_canvas_sent_at = Some(now);
+        }
+
+        #[cfg_attr(not(feature = "per_user_pps"), allow(unused_mut))]
+        if let Some(mut leaderboard) = leaderboard.publish_if_due(now, now_unix_ms) {
+            #[cfg(feature = "per_user_pps")]
+            {
+                let display_names = crate::display_names::DISPLAY_NAMES.lock().unwrap();
+                leaderboard
+                    .set_display_names(|user_id| display_names.get(user_id).map(str::to_owned));
+            }
+            canvas_state.blocking_update_leaderboard(leaderboard);
+        }
 
         if pending_update {
             nudity_image_changed_since_last_scan = true;
@@ -211,7 +770,11 @@ pub fn run_canvas_processor(
             if nudity_interval_counter >= nudity_scan_interval as u64
                 && nudity_image_changed_since_last_scan
             {
-                if let Ok(_) = nudity_image_sender.send(canvas.clone()) {
+                if let Ok(_) = nudity_image_sender.send(
+                    background
+                        .composite(&active_area(&canvas, active_width, active_height))
+                        .into_owned(),
+                ) {
                     nudity_interval_counter = 0;
                     nudity_image_changed_since_last_scan = false;
                 }
@@ -220,8 +783,16 @@ pub fn run_canvas_processor(
 
         if pending_update {
             //let start = Instant::now();
-            canvas_state.blocking_update_full_canvas(&canvas)?;
-            canvas_state.blocking_update_delta_canvas(&delta_canvas)?;
+            canvas_state.blocking_update_full_canvas(&background.composite(&active_area(
+                &canvas,
+                active_width,
+                active_height,
+            )))?;
+            canvas_state.blocking_update_delta_canvas(&active_area(
+                &delta_canvas,
+                active_width,
+                active_height,
+            ))?;
             delta_canvas = DynamicImage::new_rgba8(CANVASW.into(), CANVASH.into());
             //debug!("Encoded and updated canvas in {:?}.", start.elapsed());
             pending_update = false;
@@ -235,11 +806,24 @@ pub fn run_canvas_processor(
 pub fn run_nudity_checker(
     image_receiver: Receiver<DynamicImage>,
     canvas_state: Arc<CanvasState>,
+    censor_style: Option<CensorStyle>,
 ) -> Result<()> {
     let mut nudity_last_result_is_nude = false;
 
     while let Ok(image) = image_receiver.recv() {
         let analysis = nude::scan(&image).analyse();
+        // Rendered once per scan and served until the next one. Updated before
+        // the result is published, so clients see the new state right away.
+        if let Some(censor_style) = censor_style {
+            if analysis.nude {
+                canvas_state.blocking_update_censored_full_canvas(Some(&censor_canvas(
+                    &image,
+                    censor_style,
+                )))?;
+            } else if nudity_last_result_is_nude {
+                canvas_state.blocking_update_censored_full_canvas(None)?;
+            }
+        }
         if analysis.nude != nudity_last_result_is_nude {
             canvas_state.blocking_update_nudity_result(NudityResult {
                 is_nude: analysis.nude,
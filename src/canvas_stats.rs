@@ -0,0 +1,437 @@
+//! Statistics about the canvas itself (colors, activity, regions).
+//! The color histogram, write activity and users are tracked on every write.
+//! Only the largest regions need a scan of the canvas, so they are
+//! refreshed less often (and only if the canvas changed).
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use image::{DynamicImage, GenericImageView, Rgb};
+use serde::Serialize;
+
+use crate::palette::to_hex_color;
+
+/// Most colors to include in the histogram
+const MAX_HISTOGRAM_COLORS: usize = 256;
+const MOST_USED_COLORS: usize = 10;
+const LARGEST_REGIONS: usize = 5;
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+/// Writes are tracked per second for this long (for changed_last_hour_percent)
+const ACTIVITY_WINDOW_SECS: u64 = 60 * 60;
+/// Further users of the same day aren't counted (keeps the memory bounded)
+const MAX_USERS_TODAY: usize = 100_000;
+/// Minimum time between scans for the largest regions
+const REGIONS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone)]
+pub struct CanvasStats {
+    /// Unix time in milliseconds
+    pub generated_at_ms: u64,
+    pub distinct_colors: usize,
+    /// Sorted by usage (most used first)
+    pub color_histogram: Vec<ColorUsage>,
+    pub most_used_colors: Vec<ColorUsage>,
+    pub changed_last_minute_percent: f64,
+    pub changed_last_hour_percent: f64,
+    /// Unique users which drew since midnight (UTC), at most MAX_USERS_TODAY. None if not tracked.
+    pub unique_users_today: Option<usize>,
+    pub largest_regions: Vec<UniformRegion>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ColorUsage {
+    /// "#rrggbb"
+    pub color: String,
+    pub pixels: u32,
+    pub percent: f64,
+}
+
+/// Connected area of pixels with the same color
+#[derive(Serialize, Clone)]
+pub struct UniformRegion {
+    /// "#rrggbb"
+    pub color: String,
+    pub pixels: u32,
+    /// Bounding box
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// Pixels by the second they were last written in. Each pixel is only
+/// counted in the bucket of its last write.
+struct WriteActivity {
+    /// (unix second, pixels), indexed by the unix second modulo ACTIVITY_WINDOW_SECS
+    buckets: Vec<(u64, u32)>,
+}
+
+impl WriteActivity {
+    fn new() -> Self {
+        Self {
+            buckets: vec![(0, 0); ACTIVITY_WINDOW_SECS as usize],
+        }
+    }
+
+    /// Move a pixel from the bucket of its previous write to the one of its new write
+    #[inline]
+    fn record(&mut self, previous_written_at_ms: u64, written_at_ms: u64) {
+        let previous_second = previous_written_at_ms / 1000;
+        let bucket = &mut self.buckets[(previous_second % ACTIVITY_WINDOW_SECS) as usize];
+        if bucket.0 == previous_second {
+            bucket.1 = bucket.1.saturating_sub(1);
+        }
+        let second = written_at_ms / 1000;
+        let bucket = &mut self.buckets[(second % ACTIVITY_WINDOW_SECS) as usize];
+        if bucket.0 != second {
+            if bucket.0 > second {
+                // Older than the window (e.g. restored by a rollback)
+                return;
+            }
+            *bucket = (second, 0);
+        }
+        bucket.1 += 1;
+    }
+
+    /// Pixels last written at or after the unix second
+    fn count_since(&self, since_second: u64) -> usize {
+        self.buckets
+            .iter()
+            .filter(|(second, _)| *second >= since_second)
+            .map(|(_, pixels)| *pixels as usize)
+            .sum()
+    }
+
+    fn clear(&mut self) {
+        self.buckets.fill((0, 0));
+    }
+}
+
+pub struct CanvasStatsTracker {
+    histogram: HashMap<[u8; 3], u32>,
+    activity: WriteActivity,
+    users_today: HashSet<u64>,
+    /// Day (since unix epoch) of users_today
+    day: u64,
+    largest_regions: Vec<UniformRegion>,
+    /// Whether the canvas changed since the largest regions were found
+    regions_outdated: bool,
+    regions_refreshed_at: Option<Instant>,
+    refresh_interval: Duration,
+    last_refreshed_at: Option<Instant>,
+}
+
+impl CanvasStatsTracker {
+    pub fn new(canvas: &DynamicImage, refresh_interval: Duration) -> Self {
+        let mut tracker = Self {
+            histogram: HashMap::new(),
+            activity: WriteActivity::new(),
+            users_today: HashSet::new(),
+            day: 0,
+            largest_regions: Vec::new(),
+            regions_outdated: true,
+            regions_refreshed_at: None,
+            refresh_interval,
+            last_refreshed_at: None,
+        };
+        tracker.recount(canvas);
+        tracker
+    }
+
+    /// Rebuild the color histogram (e.g. after the canvas got reset or resized)
+    pub fn recount(&mut self, canvas: &DynamicImage) {
+        self.histogram.clear();
+        for pixel in canvas.as_rgb8().unwrap().pixels() {
+            *self.histogram.entry(pixel.0).or_insert(0) += 1;
+        }
+        self.regions_outdated = true;
+        // Right away, so the regions don't show the old canvas for long
+        self.regions_refreshed_at = None;
+    }
+
+    /// Forget when pixels were written (e.g. after the canvas got reset)
+    pub fn clear_activity(&mut self) {
+        self.activity.clear();
+    }
+
+    /// Track a pixel write. The times are unix time in milliseconds
+    /// (of the previous and this write, as in PixelProvenance).
+    #[inline]
+    pub fn record_write(
+        &mut self,
+        previous_color: Rgb<u8>,
+        color: Rgb<u8>,
+        previous_written_at_ms: u64,
+        written_at_ms: u64,
+    ) {
+        self.activity.record(previous_written_at_ms, written_at_ms);
+        if previous_color == color {
+            return;
+        }
+        self.regions_outdated = true;
+        if let Some(count) = self.histogram.get_mut(&previous_color.0) {
+            *count -= 1;
+            if *count == 0 {
+                self.histogram.remove(&previous_color.0);
+            }
+        }
+        *self.histogram.entry(color.0).or_insert(0) += 1;
+    }
+
+    #[inline]
+    pub fn record_user(&mut self, user_id: u64, now_unix_ms: u64) {
+        let day = now_unix_ms / DAY_MS;
+        if day != self.day {
+            self.users_today.clear();
+            self.day = day;
+        }
+        if self.users_today.len() < MAX_USERS_TODAY {
+            self.users_today.insert(user_id);
+        }
+    }
+
+    /// Compute the stats if the refresh interval has passed.
+    /// The canvas should only be the active area.
+    pub fn refresh_if_due(
+        &mut self,
+        now: Instant,
+        now_unix_ms: u64,
+        canvas: &DynamicImage,
+    ) -> Option<CanvasStats> {
+        if let Some(last_refreshed_at) = self.last_refreshed_at {
+            if now.saturating_duration_since(last_refreshed_at) < self.refresh_interval {
+                return None;
+            }
+        }
+        self.last_refreshed_at = Some(now);
+        if now_unix_ms / DAY_MS != self.day {
+            self.users_today.clear();
+            self.day = now_unix_ms / DAY_MS;
+        }
+
+        let total_pixels = canvas.width() as f64 * canvas.height() as f64;
+        let mut colors: Vec<_> = self.histogram.iter().collect();
+        colors.sort_unstable_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let color_histogram: Vec<_> = colors
+            .into_iter()
+            .take(MAX_HISTOGRAM_COLORS)
+            .map(|(color, pixels)| ColorUsage {
+                color: to_hex_color(Rgb(*color)),
+                pixels: *pixels,
+                percent: *pixels as f64 * 100.0 / total_pixels,
+            })
+            .collect();
+
+        let now_second = now_unix_ms / 1000;
+        let written_last_minute = self.activity.count_since(now_second.saturating_sub(60));
+        let written_last_hour = self
+            .activity
+            .count_since(now_second.saturating_sub(ACTIVITY_WINDOW_SECS));
+
+        let regions_due = self
+            .regions_refreshed_at
+            .map(|refreshed_at| {
+                now.saturating_duration_since(refreshed_at) >= REGIONS_REFRESH_INTERVAL
+            })
+            .unwrap_or(true);
+        if self.regions_outdated && regions_due {
+            self.largest_regions = find_largest_regions(canvas, LARGEST_REGIONS);
+            self.regions_outdated = false;
+            self.regions_refreshed_at = Some(now);
+        }
+
+        Some(CanvasStats {
+            generated_at_ms: now_unix_ms,
+            distinct_colors: self.histogram.len(),
+            most_used_colors: color_histogram
+                .iter()
+                .take(MOST_USED_COLORS)
+                .cloned()
+                .collect(),
+            color_histogram,
+            changed_last_minute_percent: written_last_minute as f64 * 100.0 / total_pixels,
+            changed_last_hour_percent: written_last_hour as f64 * 100.0 / total_pixels,
+            unique_users_today: cfg!(feature = "per_user_pps").then_some(self.users_today.len()),
+            largest_regions: self.largest_regions.clone(),
+        })
+    }
+}
+
+/// Find the largest areas of connected (4-neighbourhood) pixels with the same color
+fn find_largest_regions(canvas: &DynamicImage, count: usize) -> Vec<UniformRegion> {
+    let canvas = canvas.as_rgb8().unwrap();
+    let (width, height) = (canvas.width() as usize, canvas.height() as usize);
+    let mut visited = vec![false; width * height];
+    let mut stack = Vec::new();
+    let mut regions: Vec<UniformRegion> = Vec::new();
+
+    for start in 0..width * height {
+        if visited[start] {
+            continue;
+        }
+        let color = *canvas.get_pixel((start % width) as u32, (start / width) as u32);
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+        let mut pixels = 0u32;
+        visited[start] = true;
+        stack.push(start);
+        while let Some(index) = stack.pop() {
+            let (x, y) = (index % width, index / width);
+            pixels += 1;
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+
+            let neighbours = [
+                (x > 0).then(|| index - 1),
+                (x + 1 < width).then(|| index + 1),
+                (y > 0).then(|| index - width),
+                (y + 1 < height).then(|| index + width),
+            ];
+            for neighbour in neighbours.into_iter().flatten() {
+                if !visited[neighbour]
+                    && *canvas.get_pixel((neighbour % width) as u32, (neighbour / width) as u32)
+                        == color
+                {
+                    visited[neighbour] = true;
+                    stack.push(neighbour);
+                }
+            }
+        }
+
+        if regions.len() < count || pixels > regions.last().map(|r| r.pixels).unwrap_or(0) {
+            regions.push(UniformRegion {
+                color: to_hex_color(color),
+                pixels,
+                x: min_x as u16,
+                y: min_y as u16,
+                width: (max_x - min_x + 1) as u16,
+                height: (max_y - min_y + 1) as u16,
+            });
+            regions.sort_unstable_by_key(|region| Reverse(region.pixels));
+            regions.truncate(count);
+        }
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    const WHITE: Rgb<u8> = Rgb([0xFF, 0xFF, 0xFF]);
+    const RED: Rgb<u8> = Rgb([0xFF, 0, 0]);
+
+    #[test]
+    fn activity_moves_pixels_to_their_last_write() {
+        let mut activity = WriteActivity::new();
+        activity.record(0, 10_500);
+        activity.record(0, 20_000);
+        assert_eq!(activity.count_since(10), 2);
+        assert_eq!(activity.count_since(11), 1);
+
+        // The pixel written at second 10 is written again
+        activity.record(10_500, 30_000);
+        assert_eq!(activity.count_since(0), 2);
+        assert_eq!(activity.count_since(21), 1);
+    }
+
+    #[test]
+    fn activity_buckets_get_reused_after_the_window() {
+        let mut activity = WriteActivity::new();
+        activity.record(0, 5_000);
+        let later_ms = (5 + ACTIVITY_WINDOW_SECS) * 1000;
+        activity.record(0, later_ms);
+        assert_eq!(activity.count_since(0), 1);
+        assert_eq!(activity.count_since(later_ms / 1000), 1);
+
+        // Moving away from a bucket which got reused doesn't touch the new pixels
+        activity.record(5_000, later_ms + 1000);
+        assert_eq!(activity.count_since(0), 2);
+    }
+
+    #[test]
+    fn activity_ignores_writes_older_than_the_window() {
+        let mut activity = WriteActivity::new();
+        let now_ms = (10 + ACTIVITY_WINDOW_SECS) * 1000;
+        activity.record(0, now_ms);
+        activity.record(0, 10_000);
+        assert_eq!(activity.count_since(0), 1);
+
+        activity.clear();
+        assert_eq!(activity.count_since(0), 0);
+    }
+
+    #[test]
+    fn histogram_follows_writes() {
+        let canvas = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, WHITE));
+        let mut tracker = CanvasStatsTracker::new(&canvas, Duration::ZERO);
+        tracker.record_write(WHITE, RED, 0, 1_000);
+        tracker.record_write(WHITE, RED, 0, 1_000);
+        tracker.record_write(RED, WHITE, 1_000, 2_000);
+        assert_eq!(tracker.histogram.get(&WHITE.0), Some(&15));
+        assert_eq!(tracker.histogram.get(&RED.0), Some(&1));
+
+        tracker.record_write(RED, WHITE, 1_000, 3_000);
+        assert_eq!(tracker.histogram.get(&RED.0), None);
+        tracker.recount(&canvas);
+        assert_eq!(tracker.histogram.get(&WHITE.0), Some(&16));
+    }
+
+    #[test]
+    fn refresh_counts_recent_changes() {
+        let canvas = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 10, WHITE));
+        let mut tracker = CanvasStatsTracker::new(&canvas, Duration::from_secs(1));
+        let now_ms = 2 * DAY_MS;
+        tracker.record_write(WHITE, RED, 0, now_ms - 120_000);
+        tracker.record_write(WHITE, RED, 0, now_ms - 1_000);
+        tracker.record_user(7, now_ms);
+        tracker.record_user(7, now_ms);
+
+        let now = Instant::now();
+        let stats = tracker.refresh_if_due(now, now_ms, &canvas).unwrap();
+        assert_eq!(stats.changed_last_minute_percent, 1.0);
+        assert_eq!(stats.changed_last_hour_percent, 2.0);
+        assert_eq!(stats.distinct_colors, 2);
+        assert_eq!(stats.color_histogram[0].pixels, 98);
+        if cfg!(feature = "per_user_pps") {
+            assert_eq!(stats.unique_users_today, Some(1));
+        }
+        assert!(tracker.refresh_if_due(now, now_ms, &canvas).is_none());
+
+        // The next day starts without users
+        let stats = tracker
+            .refresh_if_due(now + Duration::from_secs(1), now_ms + DAY_MS, &canvas)
+            .unwrap();
+        if cfg!(feature = "per_user_pps") {
+            assert_eq!(stats.unique_users_today, Some(0));
+        }
+    }
+
+    #[test]
+    fn finds_largest_regions() {
+        let mut image = RgbImage::from_pixel(6, 4, WHITE);
+        for (x, y) in [(1, 1), (2, 1), (2, 2), (5, 3)] {
+            image.put_pixel(x, y, RED);
+        }
+        let regions = find_largest_regions(&DynamicImage::ImageRgb8(image), 2);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].pixels, 20);
+        assert_eq!(regions[1].pixels, 3);
+        assert_eq!(
+            (
+                regions[1].x,
+                regions[1].y,
+                regions[1].width,
+                regions[1].height
+            ),
+            (1, 1, 2, 2)
+        );
+        assert_eq!(regions[1].color, to_hex_color(RED));
+    }
+}
@@ -0,0 +1,41 @@
+//! Renders a censored version of the canvas which is served instead of
+//! the real one while the nudity checker considers the canvas nude.
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CensorStyle {
+    /// Scale the canvas down and back up without smoothing
+    Pixelate,
+    /// Apply a strong gaussian blur
+    Blur,
+}
+
+/// Endpoints which can serve the censored canvas
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CensoredEndpoint {
+    /// GET /canvas.png
+    CanvasPng,
+    /// The "get_full_canvas_once" websocket request
+    WsFullCanvas,
+}
+
+/// Size of one block (in pixels) when pixelating.
+const PIXELATE_BLOCK_SIZE: u32 = 16;
+/// Sigma of the gaussian blur.
+const BLUR_SIGMA: f32 = 12.0;
+
+/// Create a censored copy of the (RGB) canvas.
+pub fn censor_canvas(canvas: &DynamicImage, style: CensorStyle) -> DynamicImage {
+    let (width, height) = canvas.dimensions();
+    match style {
+        CensorStyle::Pixelate => canvas
+            .resize_exact(
+                (width / PIXELATE_BLOCK_SIZE).max(1),
+                (height / PIXELATE_BLOCK_SIZE).max(1),
+                FilterType::Triangle,
+            )
+            .resize_exact(width, height, FilterType::Nearest),
+        CensorStyle::Blur => canvas.blur(BLUR_SIGMA),
+    }
+}
@@ -1,12 +1,25 @@
 //! Defines CLI Arguments, help texts, etc.
 
+use std::path::PathBuf;
+
 use clap::Parser;
+use image::Rgba;
 use ipnet::IpNet;
 
+use crate::base_layers::CanvasFit;
+#[cfg(feature = "per_user_pps")]
+use crate::canvas_processor::GameMode;
+use crate::censor::{CensorStyle, CensoredEndpoint};
+use crate::palette::PaletteMode;
+
 fn max_canvas_fps_range(s: &str) -> Result<u16, String> {
     clap_num::number_range(s, 1, 1000)
 }
 
+fn parse_heatmap_color(s: &str) -> Result<Rgba<u8>, String> {
+    crate::heatmap::parse_hex_rgba(s).map_err(|err| err.to_string())
+}
+
 /// Listen for IPv6 pings and use them to draw on a canvas available on a webserver.
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -41,4 +54,166 @@ pub struct CliArgs {
     /// How often to scan for nudes (every N frames). 0 disables the check.
     #[arg(short, long, default_value = "10")]
     pub nude_scan_interval: u16,
+
+    /// Token to authenticate as admin (e.g. to get the uncensored canvas). Admin access is disabled when not set.
+    #[arg(long)]
+    pub admin_token: Option<String>,
+
+    /// Endpoints which serve a censored canvas while nudity is detected (admins still get the real one).
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub censored_endpoints: Vec<CensoredEndpoint>,
+
+    /// How to censor the canvas for the censored endpoints.
+    #[arg(long, value_enum, default_value = "pixelate")]
+    pub censor_style: CensorStyle,
+
+    /// JSON file with regions that pings can't draw on. Changes made using the admin api are saved to it.
+    #[arg(long)]
+    pub protected_regions: Option<PathBuf>,
+
+    /// JSON file with banned source prefixes. Reloaded when it changes and changes made using the admin api are saved to it.
+    #[arg(long)]
+    pub bans_file: Option<PathBuf>,
+
+    /// Limit how many pixels per second each user (/64 or a shorter prefix) can draw. Pixels above the limit get dropped. Sources which aren't tracked share one limit.
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long)]
+    pub rate_limit_pps: Option<f64>,
+
+    /// How many pixels a user can draw at once before the rate limit kicks in (defaults to the rate limit pps).
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long, requires = "rate_limit_pps")]
+    pub rate_limit_burst: Option<f64>,
+
+    /// "cooldown" only allows each user (/64, or a shorter prefix when using many /64s) to place one pixel per cooldown.
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long, value_enum, default_value = "free-for-all")]
+    pub game_mode: GameMode,
+
+    /// Cooldown in seconds between pixels of a user in the cooldown game mode.
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long, default_value = "300")]
+    pub pixel_cooldown: u64,
+
+    /// How many sub prefixes a prefix can have before all its users get tracked as one user
+    /// (comma separated for /40s in a /32, /48s in a /40, /56s in a /48 and /64s in a /56, each 1 to 256, where 256 never collapses).
+    #[cfg(feature = "per_user_pps")]
+    #[arg(
+        long,
+        value_delimiter = ',',
+        num_args = 4,
+        default_value = "64,64,64,16"
+    )]
+    pub pps_max_fanout: Vec<usize>,
+
+    /// How many users can be tracked at once. When full, users which were idle for a minute (and aren't on cooldown) make room for new ones. Otherwise new users aren't tracked (no user id, they share one rate limit and cooldown).
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long, default_value = "16384")]
+    pub pps_max_tracked_users: usize,
+
+    /// After how many seconds without pings users are forgotten.
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long, default_value = "3600")]
+    pub pps_user_timeout: u64,
+
+    /// How often (in seconds) to look for users to forget.
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long, default_value = "60")]
+    pub pps_cleanup_interval: u64,
+
+    /// Only allow these colors (comma separated, e.g. "#ff4500,#ffffff").
+    #[arg(long, value_delimiter = ',', conflicts_with = "palette_file")]
+    pub palette: Vec<String>,
+
+    /// Only allow the colors in this file (one "#RRGGBB" per line).
+    #[arg(long)]
+    pub palette_file: Option<PathBuf>,
+
+    /// Whether colors not in the palette get snapped to the nearest palette color or rejected.
+    #[arg(long, value_enum, default_value = "snap")]
+    pub palette_mode: PaletteMode,
+
+    /// How many pixel writes to remember for rollbacks (about 80 bytes each). Only used with an admin token. 0 disables rollbacks.
+    #[arg(long, default_value = "1000000")]
+    pub pixel_history_size: usize,
+
+    /// After how many seconds pixel writes only count half as much in the heatmap. 0 disables the heatmap.
+    #[arg(long, default_value = "60")]
+    pub heatmap_half_life: f64,
+
+    /// Colors of the heatmap from no to the most activity (comma separated "#RRGGBBAA").
+    #[arg(long, value_parser = parse_heatmap_color, value_delimiter = ',', default_value = "#0000ff00,#00ffff80,#ffff00c0,#ff0000ff")]
+    pub heatmap_colors: Vec<Rgba<u8>>,
+
+    /// How often the heatmap gets rendered (in seconds).
+    #[arg(long, default_value = "1")]
+    pub heatmap_interval: f64,
+
+    /// How often the canvas stats (/stats.json) get refreshed (in seconds).
+    #[arg(long, default_value = "5")]
+    pub stats_interval: f64,
+
+    /// How often the leaderboards (/leaderboard.json) get published (in seconds).
+    #[arg(long, default_value = "5")]
+    pub leaderboard_interval: f64,
+
+    /// How many users each leaderboard contains at most.
+    #[arg(long, default_value = "20")]
+    pub leaderboard_size: usize,
+
+    /// Directory to load templates from and save them to. Templates are lost on restart if not set.
+    #[arg(long)]
+    pub templates_dir: Option<PathBuf>,
+
+    /// Let anyone add new templates (by default only admins can).
+    #[arg(long)]
+    pub public_templates: bool,
+
+    /// How many templates non-admins can add at most.
+    #[arg(long, default_value = "100")]
+    pub max_templates: usize,
+
+    /// How many pixels (width x height) all templates together can cover at most when non-admins add one.
+    #[arg(long, default_value = "1048576")]
+    pub max_template_pixels: u64,
+
+    /// How often templates get compared to the canvas (in seconds).
+    #[arg(long, default_value = "1")]
+    pub template_check_interval: f64,
+
+    /// JSON file with events (resets, freezes and resizes) to run at given times.
+    #[arg(long)]
+    pub schedule: Option<PathBuf>,
+
+    /// Directory where the canvas gets saved before scheduled resets.
+    #[arg(long, default_value = "archive")]
+    pub archive_dir: PathBuf,
+
+    /// Image to fill the canvas with on startup (instead of white).
+    #[arg(long)]
+    pub initial_canvas: Option<PathBuf>,
+
+    /// Image which is shown wherever nobody painted yet (also after resets). Can be transparent.
+    #[arg(long)]
+    pub background_layer: Option<PathBuf>,
+
+    /// How images for the initial canvas and background layer are made to fit the canvas.
+    #[arg(long, value_enum, default_value = "resize")]
+    pub canvas_image_fit: CanvasFit,
+
+    /// File with the secret key from which public user ids are derived (created if missing).
+    /// Keep it to have the same user ids after restarts.
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long, default_value = "user_id_secret")]
+    pub user_id_secret_file: PathBuf,
+
+    /// JSON file where claimed display names are saved. Names are lost on restart if not set.
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long)]
+    pub names_file: Option<PathBuf>,
+
+    /// File with words (one per line) which display names may not contain.
+    #[cfg(feature = "per_user_pps")]
+    #[arg(long)]
+    pub name_denylist: Option<PathBuf>,
 }
@@ -0,0 +1,361 @@
+//! Display names users can claim for their public user id.
+//!
+//! A user requests a challenge (POST /names/challenge) and gets a unique
+//! address inside the public prefix. Pinging that address proves control
+//! over a /64 and binds the name to the public user id of that /64 (never to
+//! the id of a shorter prefix the /64 might be tracked by).
+//! Challenge addresses use the size nibble 0xF which is never a pixel.
+//!
+//! Ping sources can be spoofed, so this only proves that someone could send
+//! from (or spoof) the /64. To keep others from renaming a user that way, a
+//! challenge never replaces an existing name. An admin has to remove it first.
+
+use std::{
+    fs,
+    net::Ipv6Addr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::Path as UrlPath,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use color_eyre::{eyre::Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use fxhash::FxHashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::canvas::CanvasState;
+use crate::per_user_pps::PpsPublicUser;
+use crate::ping_listener::IpInfo;
+use crate::SERVER_CONFIG;
+
+pub static DISPLAY_NAMES: Lazy<Mutex<DisplayNames>> = Lazy::new(Default::default);
+/// Challenge pings from the ping listener to the claim worker
+static CHALLENGE_PINGS: Lazy<(Sender<ChallengePing>, Receiver<ChallengePing>)> =
+    Lazy::new(|| crossbeam_channel::bounded(MAX_QUEUED_CHALLENGE_PINGS));
+/// Held while saving, so an older state can't overwrite a newer one
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Size nibble (first 4 bits of the 5th segment) of challenge addresses
+const CHALLENGE_NIBBLE: u16 = 0xF;
+/// How long a challenge can be answered
+const CHALLENGE_TTL: Duration = Duration::from_secs(10 * 60);
+/// Refuse new challenges while this many are pending
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+/// Challenge pings beyond this are dropped until the claim worker caught up
+const MAX_QUEUED_CHALLENGE_PINGS: usize = 1024;
+const MIN_NAME_LEN: usize = 3;
+const MAX_NAME_LEN: usize = 24;
+
+#[derive(Default)]
+pub struct DisplayNames {
+    /// Public user id -> display name
+    names: FxHashMap<u64, String>,
+    /// Token (last 60 bits of the challenge address) -> challenge
+    challenges: FxHashMap<u64, Challenge>,
+    /// Normalized words which names may not contain
+    denylist: Vec<String>,
+    /// Names are lost on restart if not set
+    names_file: Option<PathBuf>,
+}
+
+struct Challenge {
+    name: String,
+    expires_at: Instant,
+}
+
+struct ChallengePing {
+    token: u64,
+    source: Ipv6Addr,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NamesFileEntry {
+    user_id: u64,
+    name: String,
+}
+
+/// Lowercase and without separators to make evading the denylist harder
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Get the token if the address is a challenge address
+pub fn challenge_token(address: Ipv6Addr) -> Option<u64> {
+    let s = address.segments();
+    if s[4] >> 12 != CHALLENGE_NIBBLE {
+        return None;
+    }
+    Some((s[4] as u64 & 0x0FFF) << 48 | (s[5] as u64) << 32 | (s[6] as u64) << 16 | s[7] as u64)
+}
+
+fn challenge_address(prefix: Ipv6Addr, token: u64) -> Ipv6Addr {
+    let p = prefix.segments();
+    Ipv6Addr::new(
+        p[0],
+        p[1],
+        p[2],
+        p[3],
+        CHALLENGE_NIBBLE << 12 | (token >> 48) as u16 & 0x0FFF,
+        (token >> 32) as u16,
+        (token >> 16) as u16,
+        token as u16,
+    )
+}
+
+/// Parse the public prefix ("aaaa:bbbb:cccc:dddd") shown to users
+fn parse_public_prefix(public_prefix: &str) -> Option<Ipv6Addr> {
+    Ipv6Addr::from_str(&format!("{public_prefix}::"))
+        .or_else(|_| Ipv6Addr::from_str(public_prefix))
+        .ok()
+}
+
+impl DisplayNames {
+    /// Load the names (if the file exists) and the denylist (one word per line)
+    pub fn load(names_file: Option<PathBuf>, denylist_file: Option<&Path>) -> Result<Self> {
+        let mut display_names = Self::default();
+        if let Some(path) = denylist_file {
+            display_names.denylist = fs::read_to_string(path)
+                .with_context(|| format!("Reading {path:?}"))?
+                .lines()
+                .map(normalize)
+                .filter(|word| !word.is_empty())
+                .collect();
+        }
+        if let Some(path) = names_file.as_ref().filter(|path| path.exists()) {
+            let entries: Vec<NamesFileEntry> = serde_json::from_str(
+                &fs::read_to_string(path).with_context(|| format!("Reading {path:?}"))?,
+            )
+            .with_context(|| format!("Parsing {path:?}"))?;
+            display_names.names = entries
+                .into_iter()
+                .map(|entry| (entry.user_id, entry.name))
+                .collect();
+        }
+        display_names.names_file = names_file;
+        Ok(display_names)
+    }
+
+    pub fn count(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn get(&self, user_id: u64) -> Option<&str> {
+        self.names.get(&user_id).map(String::as_str)
+    }
+
+    /// Check format, denylist and that no user has the name
+    fn validate(&self, name: &str) -> Result<(), String> {
+        if name.len() < MIN_NAME_LEN
+            || name.len() > MAX_NAME_LEN
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("Names need to be {MIN_NAME_LEN} to {MAX_NAME_LEN} characters long and may only contain a-z, A-Z, 0-9, - and _"));
+        }
+        let normalized = normalize(name);
+        if self
+            .denylist
+            .iter()
+            .any(|word| normalized.contains(word.as_str()))
+        {
+            return Err(String::from("This name is not allowed!"));
+        }
+        if self
+            .names
+            .values()
+            .any(|other_name| other_name.eq_ignore_ascii_case(name))
+        {
+            return Err(String::from("This name is already taken!"));
+        }
+        Ok(())
+    }
+
+    /// Remember a challenge for the name. Returns the token.
+    fn create_challenge(&mut self, name: String, now: Instant) -> Result<u64, String> {
+        self.validate(&name)?;
+        self.challenges
+            .retain(|_, challenge| challenge.expires_at > now);
+        if self.challenges.len() >= MAX_PENDING_CHALLENGES {
+            return Err(String::from(
+                "Too many pending challenges. Please try again later!",
+            ));
+        }
+        let mut token_bytes = [0u8; 8];
+        getrandom::getrandom(&mut token_bytes).map_err(|err| err.to_string())?;
+        let token = u64::from_be_bytes(token_bytes) & 0x0FFF_FFFF_FFFF_FFFF;
+        self.challenges.insert(
+            token,
+            Challenge {
+                name,
+                expires_at: now + CHALLENGE_TTL,
+            },
+        );
+        Ok(token)
+    }
+
+    /// Bind the name of the challenge to the user. Returns the name if it was claimed.
+    /// Users which have a name already can't get another one (see module docs).
+    fn claim(&mut self, token: u64, user_id: u64, now: Instant) -> Option<String> {
+        let challenge = self.challenges.remove(&token)?;
+        if challenge.expires_at <= now {
+            return None;
+        }
+        if let Some(existing) = self.names.get(&user_id) {
+            debug!(
+                "Display name {:?} can't be claimed: User {user_id} is already called {existing:?}",
+                challenge.name
+            );
+            return None;
+        }
+        if let Err(err) = self.validate(&challenge.name) {
+            debug!("Display name {:?} can't be claimed: {err}", challenge.name);
+            return None;
+        }
+        self.names.insert(user_id, challenge.name.clone());
+        Some(challenge.name)
+    }
+
+    /// Forget the name of a user (e.g. by an admin). Returns the removed name.
+    pub fn remove(&mut self, user_id: u64) -> Option<String> {
+        self.names.remove(&user_id)
+    }
+
+    /// Path and content of the names file (None if no names file is set)
+    fn file_contents(&self) -> Result<Option<(PathBuf, String)>> {
+        let Some(path) = &self.names_file else {
+            return Ok(None);
+        };
+        let mut entries: Vec<_> = self
+            .names
+            .iter()
+            .map(|(user_id, name)| NamesFileEntry {
+                user_id: *user_id,
+                name: name.clone(),
+            })
+            .collect();
+        entries.sort_unstable_by_key(|entry| entry.user_id);
+        Ok(Some((
+            path.clone(),
+            serde_json::to_string_pretty(&entries)?,
+        )))
+    }
+}
+
+/// Persist the names if a names file is set. Blocks, but DISPLAY_NAMES is
+/// only locked while copying the names (not while writing the file).
+pub fn save_names() -> Result<()> {
+    let _save_guard = SAVE_LOCK.lock().unwrap();
+    let Some((path, contents)) = DISPLAY_NAMES.lock().unwrap().file_contents()? else {
+        return Ok(());
+    };
+    fs::write(&path, contents).with_context(|| format!("Writing {path:?}"))
+}
+
+/// Called by the ping listener. Returns true if the ping was sent to a
+/// challenge address (and therefore isn't a pixel). The claim is handled by
+/// the claim worker, so the listener never waits for a lock or the disk.
+pub fn handle_challenge_ping(ip_info: &IpInfo) -> bool {
+    let Some(token) = challenge_token(ip_info.dest_ip) else {
+        return false;
+    };
+    CHALLENGE_PINGS
+        .0
+        .try_send(ChallengePing {
+            token,
+            source: ip_info.src_ip,
+        })
+        .ok();
+    true
+}
+
+/// Bind names to the users who answered their challenge (runs forever)
+pub fn run_claim_worker() {
+    for challenge_ping in CHALLENGE_PINGS.1.iter() {
+        // Always the id of the /64, even if it's tracked as part of a shorter prefix,
+        // so one /64 can't claim a name for everyone else in that prefix
+        let user_id = PpsPublicUser::from_prefix(challenge_ping.source, 64).id;
+        let claimed_name =
+            DISPLAY_NAMES
+                .lock()
+                .unwrap()
+                .claim(challenge_ping.token, user_id, Instant::now());
+        if let Some(name) = claimed_name {
+            info!("User {user_id} claimed the display name {name:?}");
+            if let Err(err) = save_names() {
+                error!("Failed to save display names: {err:#}");
+            }
+        }
+    }
+}
+
+type NameError = (StatusCode, String);
+
+/// Routes to be nested under /names
+pub fn router() -> Router<Arc<CanvasState>> {
+    Router::new()
+        .route("/", get(get_names))
+        .route("/challenge", post(post_challenge))
+        .route("/:user_id", get(get_name))
+}
+
+async fn get_names() -> Json<FxHashMap<u64, String>> {
+    Json(DISPLAY_NAMES.lock().unwrap().names.clone())
+}
+
+async fn get_name(UrlPath(user_id): UrlPath<u64>) -> Result<Json<NamesFileEntry>, NameError> {
+    match DISPLAY_NAMES.lock().unwrap().get(user_id) {
+        Some(name) => Ok(Json(NamesFileEntry {
+            user_id,
+            name: name.to_owned(),
+        })),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            format!("User {user_id} has no display name"),
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct ChallengeRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct ChallengeResponse {
+    /// Ping this address from the /64 which should get the name
+    address: Ipv6Addr,
+    expires_in_secs: u64,
+}
+
+async fn post_challenge(
+    Json(request): Json<ChallengeRequest>,
+) -> Result<Json<ChallengeResponse>, NameError> {
+    let public_prefix = SERVER_CONFIG.lock().unwrap().public_prefix.clone();
+    let Some(prefix) = public_prefix.as_deref().and_then(parse_public_prefix) else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            String::from("This server has no public prefix configured!"),
+        ));
+    };
+    let token = DISPLAY_NAMES
+        .lock()
+        .unwrap()
+        .create_challenge(request.name, Instant::now())
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    Ok(Json(ChallengeResponse {
+        address: challenge_address(prefix, token),
+        expires_in_secs: CHALLENGE_TTL.as_secs(),
+    }))
+}
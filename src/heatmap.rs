@@ -0,0 +1,226 @@
+//! Counts writes per pixel which decay exponentially over time
+//! and renders them as colorized overlay to show where the action currently is.
+
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::bail, Result};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::canvas::{CANVASH, CANVASW};
+
+/// Above this write weight, all heat gets rescaled (about every 20 half-lives)
+/// so the values stay within the precision of f32
+const MAX_WRITE_WEIGHT: f32 = (1 << 20) as f32;
+
+pub struct HeatmapOptions {
+    /// After how long a write only counts half as much
+    pub half_life: Duration,
+    /// Colors from no activity to the most activity (evenly spaced)
+    pub color_ramp: Vec<Rgba<u8>>,
+    /// How often to render and publish the heatmap
+    pub render_interval: Duration,
+}
+
+/// Decay is applied lazily: Instead of shrinking all heat each frame, new writes
+/// weigh more the longer ago `weighted_since` is (doubling each half-life).
+/// The actual heat of a pixel is its stored heat divided by the write weight.
+pub struct Heatmap {
+    options: HeatmapOptions,
+    heat: Vec<f32>,
+    write_weight: f32,
+    weighted_since: Instant,
+    last_rendered_at: Instant,
+}
+
+impl Heatmap {
+    pub fn new(options: HeatmapOptions, now: Instant) -> Self {
+        Self {
+            options,
+            heat: vec![0.0; CANVASW as usize * CANVASH as usize],
+            write_weight: 1.0,
+            weighted_since: now,
+            last_rendered_at: now,
+        }
+    }
+
+    #[inline]
+    pub fn record_write(&mut self, x: u16, y: u16) {
+        self.heat[y as usize * CANVASW as usize + x as usize] += self.write_weight;
+    }
+
+    /// Let the heat decay until now (only touches all pixels when rescaling)
+    pub fn decay(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.weighted_since);
+        self.write_weight = 2f32.powf(elapsed.as_secs_f32() / self.options.half_life.as_secs_f32());
+        if self.write_weight > MAX_WRITE_WEIGHT {
+            let factor = 1.0 / self.write_weight;
+            for heat in self.heat.iter_mut() {
+                *heat *= factor;
+            }
+            self.write_weight = 1.0;
+            self.weighted_since = now;
+        }
+    }
+
+    /// Render the heatmap if the render interval has passed
+    pub fn render_if_due(&mut self, now: Instant) -> Option<DynamicImage> {
+        if now.saturating_duration_since(self.last_rendered_at) < self.options.render_interval {
+            return None;
+        }
+        self.last_rendered_at = now;
+        Some(self.render())
+    }
+
+    /// Colorize the heat (on a log scale relative to the hottest pixel)
+    pub fn render(&self) -> DynamicImage {
+        let max_heat = self.heat.iter().copied().fold(0.0f32, f32::max) / self.write_weight;
+        let scale = (1.0 + max_heat).ln();
+        let mut image = RgbaImage::new(CANVASW.into(), CANVASH.into());
+        for (pixel, heat) in image.pixels_mut().zip(&self.heat) {
+            let t = if scale > 0.0 {
+                (1.0 + heat / self.write_weight).ln() / scale
+            } else {
+                0.0
+            };
+            *pixel = self.color_at(t);
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+
+    /// Interpolate the color ramp at t (0.0 to 1.0)
+    fn color_at(&self, t: f32) -> Rgba<u8> {
+        let ramp = &self.options.color_ramp;
+        if ramp.len() == 1 {
+            return ramp[0];
+        }
+        let position = t.clamp(0.0, 1.0) * (ramp.len() - 1) as f32;
+        let index = (position as usize).min(ramp.len() - 2);
+        let fraction = position - index as f32;
+        let (from, to) = (ramp[index].0, ramp[index + 1].0);
+        Rgba(
+            [0, 1, 2, 3].map(|i| {
+                (from[i] as f32 + (to[i] as f32 - from[i] as f32) * fraction).round() as u8
+            }),
+        )
+    }
+}
+
+/// Parse "#RRGGBBAA" or "#RRGGBB" (opaque)
+pub fn parse_hex_rgba(s: &str) -> Result<Rgba<u8>> {
+    let hex = s.trim().trim_start_matches('#');
+    if (hex.len() != 6 && hex.len() != 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("{s:?} is not a color like \"#RRGGBBAA\" or \"#RRGGBB\"");
+    }
+    let value = u32::from_str_radix(hex, 16)?;
+    let value = if hex.len() == 6 {
+        value << 8 | 0xFF
+    } else {
+        value
+    };
+    Ok(Rgba(value.to_be_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF_LIFE: Duration = Duration::from_secs(10);
+
+    fn heatmap(now: Instant) -> Heatmap {
+        Heatmap::new(
+            HeatmapOptions {
+                half_life: HALF_LIFE,
+                color_ramp: vec![Rgba([0, 0, 0, 0]), Rgba([0xFF, 0, 0, 0xFF])],
+                render_interval: Duration::from_secs(1),
+            },
+            now,
+        )
+    }
+
+    fn heat_at(heatmap: &Heatmap, x: u16, y: u16) -> f32 {
+        heatmap.heat[y as usize * CANVASW as usize + x as usize] / heatmap.write_weight
+    }
+
+    #[test]
+    fn heat_halves_each_half_life() {
+        let start = Instant::now();
+        let mut heatmap = heatmap(start);
+        heatmap.record_write(1, 2);
+        heatmap.record_write(1, 2);
+        assert_eq!(heat_at(&heatmap, 1, 2), 2.0);
+
+        heatmap.decay(start + HALF_LIFE);
+        assert!((heat_at(&heatmap, 1, 2) - 1.0).abs() < 1e-4);
+        heatmap.decay(start + HALF_LIFE * 3);
+        assert!((heat_at(&heatmap, 1, 2) - 0.25).abs() < 1e-4);
+        assert_eq!(heat_at(&heatmap, 2, 1), 0.0);
+    }
+
+    #[test]
+    fn new_writes_count_fully_after_decay() {
+        let start = Instant::now();
+        let mut heatmap = heatmap(start);
+        heatmap.record_write(0, 0);
+        heatmap.decay(start + HALF_LIFE);
+        heatmap.record_write(0, 0);
+        assert!((heat_at(&heatmap, 0, 0) - 1.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rescaling_keeps_the_heat() {
+        let start = Instant::now();
+        let mut heatmap = heatmap(start);
+        heatmap.record_write(3, 3);
+        // More than 20 half-lives exceed MAX_WRITE_WEIGHT
+        let later = start + HALF_LIFE * 22;
+        heatmap.decay(later);
+        assert_eq!(heatmap.write_weight, 1.0);
+        assert_eq!(heatmap.weighted_since, later);
+        let expected = 2f32.powi(-22);
+        assert!((heat_at(&heatmap, 3, 3) - expected).abs() < expected * 1e-3);
+
+        heatmap.record_write(3, 3);
+        heatmap.decay(later + HALF_LIFE);
+        assert!((heat_at(&heatmap, 3, 3) - (1.0 + expected) / 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn render_maps_hottest_pixel_to_last_color() {
+        let mut heatmap = heatmap(Instant::now());
+        heatmap.record_write(0, 0);
+        heatmap.record_write(0, 0);
+        heatmap.record_write(1, 0);
+        let rendered = heatmap.render().to_rgba8();
+        assert_eq!(*rendered.get_pixel(0, 0), Rgba([0xFF, 0, 0, 0xFF]));
+        assert_eq!(*rendered.get_pixel(2, 0), Rgba([0, 0, 0, 0]));
+        let warm = rendered.get_pixel(1, 0);
+        assert!(warm.0[0] > 0 && warm.0[0] < 0xFF);
+    }
+
+    #[test]
+    fn render_if_due_waits_for_the_interval() {
+        let start = Instant::now();
+        let mut heatmap = heatmap(start);
+        assert!(heatmap.render_if_due(start).is_none());
+        assert!(heatmap
+            .render_if_due(start + Duration::from_secs(1))
+            .is_some());
+        assert!(heatmap
+            .render_if_due(start + Duration::from_millis(1500))
+            .is_none());
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(
+            parse_hex_rgba("#11223344").unwrap(),
+            Rgba([0x11, 0x22, 0x33, 0x44])
+        );
+        assert_eq!(
+            parse_hex_rgba("aabbcc").unwrap(),
+            Rgba([0xAA, 0xBB, 0xCC, 0xFF])
+        );
+        assert!(parse_hex_rgba("#12345").is_err());
+        assert!(parse_hex_rgba("#gg0000").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Counts how many pixels each user drew over rolling windows
+//! (1 minute, 1 hour, 24 hours and all time) and how many of their
+//! pixels are still on the canvas (not overwritten yet).
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Granularity of the rolling windows
+const BUCKET_MS: u64 = 10 * 1000;
+/// Length of the rolling windows (1 minute, 1 hour and 24 hours)
+const WINDOWS_MS: [u64; 3] = [60 * 1000, 60 * 60 * 1000, 24 * 60 * 60 * 1000];
+/// Forget the users with the least pixels if more users are known (all time)
+const MAX_ALL_TIME_USERS: usize = 100_000;
+
+#[derive(Serialize, Clone)]
+pub struct Leaderboard {
+    /// Unix time in milliseconds
+    pub generated_at_ms: u64,
+    pub last_minute: Vec<LeaderboardEntry>,
+    pub last_hour: Vec<LeaderboardEntry>,
+    pub last_day: Vec<LeaderboardEntry>,
+    pub all_time: Vec<LeaderboardEntry>,
+    /// Pixels of the user which are still on the canvas
+    pub survived: Vec<LeaderboardEntry>,
+}
+
+impl Leaderboard {
+    /// Fill in the display names of all entries
+    #[cfg_attr(not(feature = "per_user_pps"), allow(unused))]
+    pub fn set_display_names(&mut self, display_name: impl Fn(u64) -> Option<String>) {
+        for entries in [
+            &mut self.last_minute,
+            &mut self.last_hour,
+            &mut self.last_day,
+            &mut self.all_time,
+            &mut self.survived,
+        ] {
+            for entry in entries {
+                entry.display_name = display_name(entry.user_id);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct LeaderboardEntry {
+    /// Public user id (see per_user_pps.rs)
+    pub user_id: u64,
+    pub pixels: u64,
+    /// Set if the user has a display name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+pub struct LeaderboardTracker {
+    /// Pixels per user in each bucket of the last 24 hours (oldest first)
+    buckets: VecDeque<(u64, HashMap<u64, u64>)>,
+    /// Totals per user of each window in WINDOWS_MS
+    window_totals: [HashMap<u64, u64>; 3],
+    /// Buckets before this one are already subtracted from the window totals
+    window_starts: [u64; 3],
+    all_time: HashMap<u64, u64>,
+    /// Pixels currently on the canvas per user
+    survived: HashMap<u64, u64>,
+    size: usize,
+    publish_interval: Duration,
+    last_published_at: Option<Instant>,
+}
+
+fn add(map: &mut HashMap<u64, u64>, user_id: u64, pixels: u64) {
+    *map.entry(user_id).or_insert(0) += pixels;
+}
+
+fn subtract(map: &mut HashMap<u64, u64>, user_id: u64, pixels: u64) {
+    if let Some(count) = map.get_mut(&user_id) {
+        *count = count.saturating_sub(pixels);
+        if *count == 0 {
+            map.remove(&user_id);
+        }
+    }
+}
+
+impl LeaderboardTracker {
+    /// `size` is the max amount of entries per leaderboard
+    pub fn new(size: usize, publish_interval: Duration) -> Self {
+        Self {
+            buckets: VecDeque::new(),
+            window_totals: Default::default(),
+            window_starts: [0; 3],
+            all_time: HashMap::new(),
+            survived: HashMap::new(),
+            size,
+            publish_interval,
+            last_published_at: None,
+        }
+    }
+
+    /// Count a pixel written by user_id which replaced a pixel of previous_user_id
+    #[inline]
+    pub fn record_write(
+        &mut self,
+        user_id: Option<u64>,
+        previous_user_id: Option<u64>,
+        now_ms: u64,
+    ) {
+        self.record_owner_change(user_id, previous_user_id);
+        let Some(user_id) = user_id else {
+            return;
+        };
+        let last_bucket = self.buckets.back().map(|(index, _)| *index);
+        // Buckets need to stay sorted even if the clock goes backwards
+        let bucket = (now_ms / BUCKET_MS).max(last_bucket.unwrap_or(0));
+        if last_bucket != Some(bucket) {
+            self.buckets.push_back((bucket, HashMap::new()));
+        }
+        add(&mut self.buckets.back_mut().unwrap().1, user_id, 1);
+        for totals in &mut self.window_totals {
+            add(totals, user_id, 1);
+        }
+        add(&mut self.all_time, user_id, 1);
+    }
+
+    /// A pixel now belongs to user_id instead of previous_user_id (without being drawn, e.g. rollbacks)
+    #[inline]
+    pub fn record_owner_change(&mut self, user_id: Option<u64>, previous_user_id: Option<u64>) {
+        if user_id == previous_user_id {
+            return;
+        }
+        if let Some(previous_user_id) = previous_user_id {
+            subtract(&mut self.survived, previous_user_id, 1);
+        }
+        if let Some(user_id) = user_id {
+            add(&mut self.survived, user_id, 1);
+        }
+    }
+
+    /// Nobody owns any pixels anymore (the canvas got reset)
+    pub fn clear_survived(&mut self) {
+        self.survived.clear();
+    }
+
+    /// Subtract buckets which are no longer inside the windows
+    fn expire(&mut self, now_ms: u64) {
+        let current_bucket = now_ms / BUCKET_MS;
+        for ((totals, window_start), window_ms) in self
+            .window_totals
+            .iter_mut()
+            .zip(&mut self.window_starts)
+            .zip(WINDOWS_MS)
+        {
+            let new_window_start = (current_bucket + 1).saturating_sub(window_ms / BUCKET_MS);
+            if new_window_start <= *window_start {
+                continue;
+            }
+            let from = self
+                .buckets
+                .partition_point(|(index, _)| *index < *window_start);
+            let to = self
+                .buckets
+                .partition_point(|(index, _)| *index < new_window_start);
+            for (_, counts) in self.buckets.range(from..to) {
+                for (user_id, pixels) in counts {
+                    subtract(totals, *user_id, *pixels);
+                }
+            }
+            *window_start = new_window_start;
+        }
+        // The largest window has all buckets subtracted that are now unused
+        let oldest_needed = self.window_starts.iter().copied().min().unwrap_or(0);
+        while self
+            .buckets
+            .front()
+            .map(|(index, _)| *index < oldest_needed)
+            .unwrap_or(false)
+        {
+            self.buckets.pop_front();
+        }
+
+        if self.all_time.len() > MAX_ALL_TIME_USERS {
+            let mut counts: Vec<u64> = self.all_time.values().copied().collect();
+            let threshold_index = counts.len() - MAX_ALL_TIME_USERS / 2;
+            let (_, threshold, _) = counts.select_nth_unstable(threshold_index);
+            let threshold = *threshold;
+            self.all_time.retain(|_, pixels| *pixels > threshold);
+        }
+    }
+
+    /// Create the leaderboards if the publish interval has passed
+    pub fn publish_if_due(&mut self, now: Instant, now_ms: u64) -> Option<Leaderboard> {
+        if let Some(last_published_at) = self.last_published_at {
+            if now.saturating_duration_since(last_published_at) < self.publish_interval {
+                return None;
+            }
+        }
+        self.last_published_at = Some(now);
+        self.expire(now_ms);
+        Some(Leaderboard {
+            generated_at_ms: now_ms,
+            last_minute: self.top(&self.window_totals[0]),
+            last_hour: self.top(&self.window_totals[1]),
+            last_day: self.top(&self.window_totals[2]),
+            all_time: self.top(&self.all_time),
+            survived: self.top(&self.survived),
+        })
+    }
+
+    fn top(&self, totals: &HashMap<u64, u64>) -> Vec<LeaderboardEntry> {
+        let mut entries: Vec<_> = totals
+            .iter()
+            .map(|(user_id, pixels)| (*user_id, *pixels))
+            .collect();
+        entries.sort_unstable_by_key(|(user_id, pixels)| (Reverse(*pixels), *user_id));
+        entries
+            .into_iter()
+            .take(self.size)
+            .map(|(user_id, pixels)| LeaderboardEntry {
+                user_id,
+                pixels,
+                display_name: None,
+            })
+            .collect()
+    }
+}
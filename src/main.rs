@@ -1,11 +1,30 @@
 //! Main method (obviously), most of webserver routes and kicking off other threads.
 
+mod admin;
+mod admin_ws;
+mod bans;
+mod base_layers;
 mod canvas;
 mod canvas_processor;
+mod canvas_stats;
+mod censor;
 mod cli_args;
 #[cfg(feature = "per_user_pps")]
+mod display_names;
+mod heatmap;
+mod leaderboard;
+mod palette;
+#[cfg(feature = "per_user_pps")]
 mod per_user_pps;
 mod ping_listener;
+mod pixel_events;
+mod pixel_history;
+mod pixel_provenance;
+mod pps_history;
+mod protected_regions;
+mod scheduler;
+mod templates;
+mod viewport;
 mod websocket_handler;
 
 use crate::canvas::CANVASH;
@@ -13,19 +32,31 @@ use crate::canvas::CANVASW;
 use axum::extract::ConnectInfo;
 use axum::http::HeaderMap;
 use axum::{
-    extract::{Query, State},
-    http::header,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
     response::{AppendHeaders, IntoResponse},
     routing::get,
     Json, Router,
 };
+use bans::Ban;
 use canvas::CanvasState;
+use canvas_processor::{GameMode, ProcessorOptions};
+use canvas_stats::CanvasStats;
+use censor::CensoredEndpoint;
 use clap::Parser;
 use cli_args::CliArgs;
 use color_eyre::{eyre::Context, Result};
+use heatmap::HeatmapOptions;
 use ipnet::IpNet;
+use leaderboard::Leaderboard;
+use palette::{Palette, PaletteMode};
+use pixel_provenance::PixelOwner;
+use pps_history::PpsHistory;
+use protected_regions::ProtectedRegion;
+use scheduler::ScheduledEvent;
 use serde::{Deserialize, Serialize};
 use std::net::Ipv6Addr;
+use std::path::PathBuf;
 use std::{
     net::{IpAddr, SocketAddr},
     str::FromStr,
@@ -46,10 +77,47 @@ struct ServerConfig {
     width: u16,
     height: u16,
     built_with_per_user_pps_support: bool,
+    rate_limit_pps: Option<f64>,
+    rate_limit_burst: Option<f64>,
+    game_mode: GameMode,
+    /// Only set for the cooldown game mode
+    pixel_cooldown_secs: Option<u64>,
+    /// Colors as "#rrggbb" if only these are allowed
+    palette: Option<Vec<String>>,
+    palette_mode: Option<PaletteMode>,
+    /// None if the heatmap is disabled
+    heatmap_half_life_secs: Option<f64>,
+    /// Unix time in milliseconds until which no pixels are accepted
+    frozen_until_ms: Option<u64>,
+    /// Upcoming events that were already announced
+    scheduled_events: Vec<ScheduledEvent>,
+    /// Whether only admins can add templates
+    templates_admin_only: bool,
+    max_templates: usize,
+    /// Max width x height of all templates together (for non-admins)
+    max_template_pixels: u64,
+    #[serde(skip)]
+    templates_dir: Option<PathBuf>,
+    #[serde(serialize_with = "protected_regions::serialize_public")]
+    protected_regions: Vec<ProtectedRegion>,
+    #[serde(skip)]
+    protected_regions_file: Option<PathBuf>,
+    #[serde(skip)]
+    bans: Vec<Ban>,
+    #[serde(skip)]
+    bans_file: Option<PathBuf>,
     #[serde(skip)]
     trusted_proxy_ranges: Vec<IpNet>,
     #[serde(skip)]
     trusted_cloudflare_ranges: Vec<IpNet>,
+    #[serde(skip)]
+    admin_token: Option<String>,
+    #[serde(skip)]
+    censored_endpoints: Vec<CensoredEndpoint>,
+    /// Used by the canvas processor (admins change it with ProcessorCommand::SetTrackingOptions)
+    #[cfg(feature = "per_user_pps")]
+    #[serde(skip)]
+    tracking_options: Option<per_user_pps::TrackingOptions>,
 }
 
 static SERVER_CONFIG: Mutex<ServerConfig> = Mutex::new(ServerConfig {
@@ -61,8 +129,29 @@ static SERVER_CONFIG: Mutex<ServerConfig> = Mutex::new(ServerConfig {
     } else {
         false
     },
+    rate_limit_pps: None,
+    rate_limit_burst: None,
+    game_mode: GameMode::FreeForAll,
+    pixel_cooldown_secs: None,
+    palette: None,
+    palette_mode: None,
+    heatmap_half_life_secs: None,
+    frozen_until_ms: None,
+    scheduled_events: vec![],
+    templates_admin_only: true,
+    max_templates: 0,
+    max_template_pixels: 0,
+    templates_dir: None,
+    protected_regions: vec![],
+    protected_regions_file: None,
+    bans: vec![],
+    bans_file: None,
     trusted_proxy_ranges: vec![],
     trusted_cloudflare_ranges: vec![],
+    admin_token: None,
+    censored_endpoints: vec![],
+    #[cfg(feature = "per_user_pps")]
+    tracking_options: None,
 });
 
 #[tokio::main]
@@ -75,7 +164,204 @@ async fn main() -> Result<()> {
     }
     tracing_subscriber::fmt::init();
 
+    let censor_style = if args.censored_endpoints.is_empty() {
+        None
+    } else {
+        Some(args.censor_style)
+    };
+
+    if let Some(protected_regions_file) = &args.protected_regions {
+        let protected_regions = protected_regions::load_from_file(protected_regions_file)
+            .context("Loading protected regions")?;
+        info!(
+            "Loaded {} protected regions from {protected_regions_file:?}",
+            protected_regions.len()
+        );
+        let mut server_config = SERVER_CONFIG.lock().unwrap();
+        protected_regions::publish(&protected_regions);
+        server_config.protected_regions = protected_regions;
+        server_config.protected_regions_file = Some(protected_regions_file.clone());
+    }
+
+    if let Some(bans_file) = &args.bans_file {
+        let bans = bans::load_from_file(bans_file).context("Loading bans")?;
+        info!("Loaded {} bans from {bans_file:?}", bans.len());
+        let mut server_config = SERVER_CONFIG.lock().unwrap();
+        bans::publish(&bans);
+        server_config.bans = bans;
+        server_config.bans_file = Some(bans_file.clone());
+        let bans_file = bans_file.clone();
+        std::thread::Builder::new()
+            .name("Bans-Watcher".to_owned())
+            .spawn(move || {
+                if let Err(err) = bans::run_bans_file_watcher(bans_file) {
+                    error!("Bans-Watcher crashed: {err:#}");
+                }
+            })?;
+    }
+
+    #[cfg(feature = "per_user_pps")]
+    per_user_pps::load_or_create_user_id_key(&args.user_id_secret_file)
+        .context("Loading user id secret")?;
+
+    #[cfg(feature = "per_user_pps")]
+    {
+        let display_names = display_names::DisplayNames::load(
+            args.names_file.clone(),
+            args.name_denylist.as_deref(),
+        )
+        .context("Loading display names")?;
+        info!("Loaded {} display names", display_names.count());
+        *display_names::DISPLAY_NAMES.lock().unwrap() = display_names;
+        std::thread::Builder::new()
+            .name("Display-Names".to_owned())
+            .spawn(display_names::run_claim_worker)?;
+    }
+
+    #[cfg(feature = "per_user_pps")]
+    let rate_limit = match args.rate_limit_pps {
+        Some(pixels_per_second) => {
+            let burst = args.rate_limit_burst.unwrap_or(pixels_per_second);
+            color_eyre::eyre::ensure!(
+                pixels_per_second > 0.0 && burst >= 1.0,
+                "The rate limit pps must be positive and the burst at least 1!"
+            );
+            let mut server_config = SERVER_CONFIG.lock().unwrap();
+            server_config.rate_limit_pps = Some(pixels_per_second);
+            server_config.rate_limit_burst = Some(burst);
+            Some(per_user_pps::RateLimit {
+                pixels_per_second,
+                burst,
+            })
+        }
+        None => None,
+    };
+    #[cfg(feature = "per_user_pps")]
+    let tracking_options = {
+        let tracking_options =
+            per_user_pps::TrackingOptions {
+                max_fanout: args.pps_max_fanout.clone().try_into().map_err(|_| {
+                    color_eyre::eyre::eyre!("--pps-max-fanout needs exactly 4 values")
+                })?,
+                max_tracked_users: args.pps_max_tracked_users,
+                user_timeout: Duration::from_secs(args.pps_user_timeout),
+                cleanup_interval: Duration::from_secs(args.pps_cleanup_interval),
+            };
+        tracking_options
+            .validate()
+            .context("Invalid per user pps thresholds")?;
+        info!("Per user pps tracking: {tracking_options:?}");
+        SERVER_CONFIG.lock().unwrap().tracking_options = Some(tracking_options);
+        tracking_options
+    };
+    #[cfg(feature = "per_user_pps")]
+    let pixel_cooldown = match args.game_mode {
+        GameMode::FreeForAll => None,
+        GameMode::Cooldown => {
+            let mut server_config = SERVER_CONFIG.lock().unwrap();
+            server_config.game_mode = GameMode::Cooldown;
+            server_config.pixel_cooldown_secs = Some(args.pixel_cooldown);
+            Some(Duration::from_secs(args.pixel_cooldown))
+        }
+    };
+
+    let palette = if let Some(palette_file) = &args.palette_file {
+        Some(Palette::load_from_file(palette_file, args.palette_mode)?)
+    } else if !args.palette.is_empty() {
+        let colors = args
+            .palette
+            .iter()
+            .map(|color| palette::parse_hex_color(color))
+            .collect::<Result<Vec<_>>>()
+            .context("Parsing palette")?;
+        Some(Palette::new(colors, args.palette_mode)?)
+    } else {
+        None
+    };
+    if let Some(palette) = &palette {
+        info!(
+            "Enforcing a palette of {} colors ({:?})",
+            palette.to_hex_colors().len(),
+            palette.mode()
+        );
+        let mut server_config = SERVER_CONFIG.lock().unwrap();
+        server_config.palette = Some(palette.to_hex_colors());
+        server_config.palette_mode = Some(palette.mode());
+    }
+
+    let heatmap = if args.heatmap_half_life > 0.0 {
+        color_eyre::eyre::ensure!(
+            !args.heatmap_colors.is_empty() && args.heatmap_interval > 0.0,
+            "The heatmap needs at least one color and a positive interval!"
+        );
+        SERVER_CONFIG.lock().unwrap().heatmap_half_life_secs = Some(args.heatmap_half_life);
+        Some(HeatmapOptions {
+            half_life: Duration::from_secs_f64(args.heatmap_half_life),
+            color_ramp: args.heatmap_colors.clone(),
+            render_interval: Duration::from_secs_f64(args.heatmap_interval),
+        })
+    } else {
+        None
+    };
+
+    color_eyre::eyre::ensure!(
+        args.stats_interval > 0.0
+            && args.template_check_interval > 0.0
+            && args.leaderboard_interval > 0.0,
+        "The stats, template check and leaderboard intervals have to be positive!"
+    );
+
+    let schedule = match &args.schedule {
+        Some(schedule_file) => {
+            let schedule = scheduler::load_from_file(schedule_file)
+                .with_context(|| format!("Loading schedule from {schedule_file:?}"))?;
+            info!(
+                "Loaded {} scheduled events from {schedule_file:?}",
+                schedule.len()
+            );
+            schedule
+        }
+        None => vec![],
+    };
+
+    let initial_canvas = match &args.initial_canvas {
+        Some(path) => Some(
+            base_layers::load_fitted(path, args.canvas_image_fit)
+                .context("Loading initial canvas")?,
+        ),
+        None => None,
+    };
+    let background_layer = match &args.background_layer {
+        Some(path) => Some(
+            base_layers::load_fitted(path, args.canvas_image_fit)
+                .context("Loading background layer")?,
+        ),
+        None => None,
+    };
+
     let canvas_state = Arc::new(CanvasState::default());
+    if let Some(templates_dir) = &args.templates_dir {
+        let templates = templates::load_from_dir(templates_dir)
+            .with_context(|| format!("Loading templates from {templates_dir:?}"))?;
+        info!(
+            "Loaded {} templates from {templates_dir:?}",
+            templates.len()
+        );
+        *canvas_state.write_templates().await = templates;
+    }
+    {
+        let mut server_config = SERVER_CONFIG.lock().unwrap();
+        server_config.templates_admin_only = !args.public_templates;
+        server_config.max_templates = args.max_templates;
+        server_config.max_template_pixels = args.max_template_pixels;
+        server_config.templates_dir = args.templates_dir.clone();
+    }
+    // Only admins can roll back
+    let pixel_history_size = if args.admin_token.is_some() {
+        args.pixel_history_size
+    } else {
+        0
+    };
     let canvas_state_clone = canvas_state.clone();
     let (pixel_sender, pixel_receiver) = crossbeam_channel::unbounded();
     std::thread::Builder::new()
@@ -96,8 +382,31 @@ async fn main() -> Result<()> {
             if let Err(err) = canvas_processor::run_canvas_processor(
                 pixel_receiver,
                 canvas_state_clone,
-                Duration::from_nanos(1_000_000_000 / args.max_canvas_fps as u64),
-                args.nude_scan_interval,
+                ProcessorOptions {
+                    update_interval: Duration::from_nanos(
+                        1_000_000_000 / args.max_canvas_fps as u64,
+                    ),
+                    nudity_scan_interval: args.nude_scan_interval,
+                    censor_style,
+                    palette,
+                    pixel_history_size,
+                    heatmap,
+                    stats_interval: Duration::from_secs_f64(args.stats_interval),
+                    leaderboard_interval: Duration::from_secs_f64(args.leaderboard_interval),
+                    leaderboard_size: args.leaderboard_size,
+                    template_check_interval: Duration::from_secs_f64(args.template_check_interval),
+                    initial_canvas,
+                    background_layer,
+                    schedule,
+                    archive_dir: args.archive_dir.clone(),
+                    #[cfg(feature = "per_user_pps")]
+                    user_limits: per_user_pps::UserLimits {
+                        rate_limit,
+                        pixel_cooldown,
+                    },
+                    #[cfg(feature = "per_user_pps")]
+                    tracking_options,
+                },
             ) {
                 error!("Canvas-Processor crashed: {err:#}");
                 std::process::exit(1);
@@ -106,6 +415,8 @@ async fn main() -> Result<()> {
 
     SERVER_CONFIG.lock().unwrap().public_prefix = args.public_prefix.clone();
     SERVER_CONFIG.lock().unwrap().trusted_proxy_ranges = args.trusted_proxy_ranges.clone();
+    SERVER_CONFIG.lock().unwrap().admin_token = args.admin_token.clone();
+    SERVER_CONFIG.lock().unwrap().censored_endpoints = args.censored_endpoints.clone();
     // TODO: Add automated way to retreives these ranges. Otherwise this will break at some point or be come a security hole!
     SERVER_CONFIG.lock().unwrap().trusted_cloudflare_ranges = vec![
         // https://www.cloudflare.com/ips-v6
@@ -134,11 +445,27 @@ async fn main() -> Result<()> {
         IpNet::from_str("131.0.72.0/22").unwrap(),
     ];
 
-    let app = Router::new()
+    #[cfg_attr(not(feature = "per_user_pps"), allow(unused_mut))]
+    let mut app = Router::new()
         .route("/ws", get(websocket_handler::get_ws))
         .route("/canvas.png", get(get_canvas))
+        .route("/heatmap.png", get(get_heatmap))
+        .route("/stats.json", get(get_stats))
+        .route("/leaderboard.json", get(get_leaderboard))
+        .route("/pps_history.json", get(get_pps_history))
         .route("/serverconfig.json", get(get_server_config))
         .route("/my_user_id", get(get_my_user_id))
+        .route("/pixel/:x/:y", get(get_pixel_owner))
+        .route("/pixel_events", get(pixel_events::get_pixel_events))
+        .nest("/admin", admin::router())
+        .nest("/templates", templates::router());
+    #[cfg(feature = "per_user_pps")]
+    {
+        app = app
+            .nest("/names", display_names::router())
+            .route("/per_user_pps_metrics.json", get(get_per_user_pps_metrics));
+    }
+    let app = app
         .fallback_service(ServeDir::new("./static"))
         .with_state(canvas_state)
         .layer(
@@ -174,22 +501,103 @@ async fn main() -> Result<()> {
 struct CanvasQueryParams {
     #[serde(default)]
     allow_cache: bool,
+    admin_token: Option<String>,
 }
 
 async fn get_canvas(
     State(canvas_state): State<Arc<CanvasState>>,
     Query(params): Query<CanvasQueryParams>,
+    request_headers: HeaderMap,
 ) -> impl IntoResponse {
     let mut headers = vec![(header::CONTENT_TYPE, "image/png")];
     if !params.allow_cache {
         headers.push((header::CACHE_CONTROL, "no-store"));
     }
+    let censor = is_censored_endpoint(CensoredEndpoint::CanvasPng)
+        && !admin::is_admin(&request_headers, params.admin_token.as_deref());
     (
         AppendHeaders(headers),
-        canvas_state.read_encoded_full_canvas().await.get_encoded(),
+        canvas_state.get_encoded_full_canvas(censor).await,
     )
 }
 
+async fn get_heatmap(
+    State(canvas_state): State<Arc<CanvasState>>,
+    Query(params): Query<CanvasQueryParams>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if SERVER_CONFIG
+        .lock()
+        .unwrap()
+        .heatmap_half_life_secs
+        .is_none()
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            String::from("The heatmap is disabled on this server!"),
+        ));
+    }
+    let mut headers = vec![(header::CONTENT_TYPE, "image/png")];
+    if !params.allow_cache {
+        headers.push((header::CACHE_CONTROL, "no-store"));
+    }
+    Ok((
+        AppendHeaders(headers),
+        canvas_state.read_encoded_heatmap().await.get_encoded(),
+    ))
+}
+
+async fn get_stats(
+    State(canvas_state): State<Arc<CanvasState>>,
+) -> Result<Json<CanvasStats>, (StatusCode, String)> {
+    match canvas_state.stats().await {
+        Some(stats) => Ok(Json(stats.as_ref().clone())),
+        None => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            String::from("The stats were not computed yet!"),
+        )),
+    }
+}
+
+async fn get_leaderboard(
+    State(canvas_state): State<Arc<CanvasState>>,
+) -> Result<Json<Leaderboard>, (StatusCode, String)> {
+    match canvas_state.leaderboard().await {
+        Some(leaderboard) => Ok(Json(leaderboard.as_ref().clone())),
+        None => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            String::from("The leaderboard was not published yet!"),
+        )),
+    }
+}
+
+async fn get_pps_history(State(canvas_state): State<Arc<CanvasState>>) -> Json<PpsHistory> {
+    Json(canvas_state.pps_history().await.clone())
+}
+
+#[cfg(feature = "per_user_pps")]
+async fn get_per_user_pps_metrics() -> Json<per_user_pps::PpsTrackingMetrics> {
+    Json(per_user_pps::metrics())
+}
+
+/// Whether this endpoint should serve the censored canvas while nudity is detected
+pub fn is_censored_endpoint(endpoint: CensoredEndpoint) -> bool {
+    SERVER_CONFIG
+        .lock()
+        .unwrap()
+        .censored_endpoints
+        .contains(&endpoint)
+}
+
+async fn get_pixel_owner(
+    State(canvas_state): State<Arc<CanvasState>>,
+    Path((x, y)): Path<(u16, u16)>,
+) -> Result<Json<PixelOwner>, (StatusCode, String)> {
+    canvas_state.pixel_provenance().get(x, y).map(Json).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("The pixel {x}, {y} is outside of the canvas!"),
+    ))
+}
+
 async fn get_server_config() -> Json<ServerConfig> {
     Json(SERVER_CONFIG.lock().unwrap().clone())
 }
@@ -201,6 +609,11 @@ enum MyUserIdResponse {
     Success {
         ip: Ipv6Addr,
         user_id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        display_name: Option<String>,
+        /// Only set in the cooldown game mode
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cooldown_remaining_ms: Option<u64>,
     },
     Error {
         error: String,
@@ -231,20 +644,22 @@ async fn get_my_user_id(
             IpAddr::V6(ipv6_addr) => Some(ipv6_addr.clone()),
         };
         if let Some(user_ip) = user_ip {
-            let mut maybe_user_id = None;
-            if let Ok(ref mut pps_users) = per_user_pps::PPS_USERS.lock() {
-                let maybe_user_info = per_user_pps::find_user_info_data(pps_users, user_ip);
-                if let Some(user_info) = maybe_user_info {
-                    maybe_user_id = Some(user_info.get_user_id().id);
-                }
-            } else {
-                warn!("Failed to lock PPS_USERS! Will simply not return user id for now.");
-            }
-
-            if let Some(user_id) = maybe_user_id {
+            if let Some(user) = per_user_pps::find_user(user_ip) {
+                let user_id = user.user_id.id;
+                let cooldown_remaining = user.cooldown_remaining(std::time::Instant::now());
+                let is_cooldown_mode =
+                    SERVER_CONFIG.lock().unwrap().game_mode == GameMode::Cooldown;
                 Json(MyUserIdResponse::Success {
                     ip: user_ip,
                     user_id,
+                    // Names belong to the /64 (also if it's tracked by a shorter prefix)
+                    display_name: display_names::DISPLAY_NAMES
+                        .lock()
+                        .unwrap()
+                        .get(per_user_pps::PpsPublicUser::from_prefix(user_ip, 64).id)
+                        .map(str::to_owned),
+                    cooldown_remaining_ms: is_cooldown_mode
+                        .then(|| cooldown_remaining.unwrap_or_default().as_millis() as u64),
                 })
             } else {
                 Json(MyUserIdResponse::ErrorWithIp { ip: user_ip, error: String::from("Didn't find any user id for your ip. Either you never pinged this server or it was too long ago.") })
@@ -0,0 +1,100 @@
+//! Fixed color palette which pixels have to use (e.g. for themed events).
+
+use std::path::Path;
+
+use color_eyre::{
+    eyre::{bail, ensure, Context},
+    Result,
+};
+use image::Rgb;
+
+#[derive(clap::ValueEnum, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaletteMode {
+    /// Replace colors with the nearest palette color
+    Snap,
+    /// Drop pixels which don't use a palette color
+    Reject,
+}
+
+#[derive(Clone)]
+pub struct Palette {
+    colors: Vec<Rgb<u8>>,
+    mode: PaletteMode,
+}
+
+impl Palette {
+    pub fn new(colors: Vec<Rgb<u8>>, mode: PaletteMode) -> Result<Self> {
+        ensure!(!colors.is_empty(), "The palette has no colors");
+        Ok(Self { colors, mode })
+    }
+
+    /// Load a file with one color per line ("#RRGGBB"). Empty lines and lines starting with "//" are ignored.
+    pub fn load_from_file(path: &Path, mode: PaletteMode) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Reading palette {path:?}"))?;
+        let colors = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("//"))
+            .map(parse_hex_color)
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("Parsing palette {path:?}"))?;
+        Self::new(colors, mode)
+    }
+
+    /// Returns the color to draw instead or None if the pixel should be dropped
+    #[inline]
+    pub fn apply(&self, color: Rgb<u8>) -> Option<Rgb<u8>> {
+        if self.colors.contains(&color) {
+            return Some(color);
+        }
+        match self.mode {
+            PaletteMode::Reject => None,
+            PaletteMode::Snap => self
+                .colors
+                .iter()
+                .min_by_key(|palette_color| color_distance(**palette_color, color))
+                .copied(),
+        }
+    }
+
+    pub fn mode(&self) -> PaletteMode {
+        self.mode
+    }
+
+    /// Colors as "#rrggbb"
+    pub fn to_hex_colors(&self) -> Vec<String> {
+        self.colors.iter().copied().map(to_hex_color).collect()
+    }
+}
+
+/// Format as "#rrggbb"
+pub fn to_hex_color(Rgb([r, g, b]): Rgb<u8>) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// For use with #[serde(serialize_with = "...")]
+pub fn serialize_hex_color<S: serde::Serializer>(
+    color: &Rgb<u8>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex_color(*color))
+}
+
+/// Parse "#RRGGBB" or "RRGGBB"
+pub fn parse_hex_color(s: &str) -> Result<Rgb<u8>> {
+    let hex = s.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("{s:?} is not a color like \"#RRGGBB\"");
+    }
+    let value = u32::from_str_radix(hex, 16)?;
+    Ok(Rgb([(value >> 16) as u8, (value >> 8) as u8, value as u8]))
+}
+
+/// Squared distance weighted by how sensitive the eye is to each channel ("redmean")
+fn color_distance(a: Rgb<u8>, b: Rgb<u8>) -> u32 {
+    let red_mean = (a.0[0] as u32 + b.0[0] as u32) / 2;
+    let [dr, dg, db] = [0, 1, 2].map(|i| (a.0[i] as i32 - b.0[i] as i32).pow(2) as u32);
+    (((512 + red_mean) * dr) >> 8) + 4 * dg + (((767 - red_mean) * db) >> 8)
+}
@@ -1,229 +1,961 @@
 //! Track PPS per user.
 //! A lot of fail safes are built-in to prevent abuse by people with
 //! a lot of IPs, spoofing random ones or other kinds of silliness.
+//!
+//! The canvas processor owns all users (PpsUsers) and regularly publishes a
+//! snapshot of them. Lookups (e.g. for /my_user_id) only read the latest
+//! snapshot, so they never block the canvas processor or the other way around.
+//!
+//! Public user ids are a keyed hash (SipHash) of the users prefix. They stay the
+//! same across restarts, but can't be linked to the prefix without the secret key.
 
+use arc_swap::ArcSwap;
+use color_eyre::{
+    eyre::{ensure, Context},
+    Result,
+};
 use fxhash::FxHashMap;
-use once_cell::sync::Lazy;
+use ipnet::Ipv6Net;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use siphasher::sip::SipHasher24;
 use std::{
+    collections::BTreeMap,
+    hash::Hasher,
+    io::Write,
     net::Ipv6Addr,
-    sync::Mutex,
+    path::Path,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
+use crate::canvas::CooldownEvent;
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PpsPublicUser {
     pub id: u64,
 }
-pub static PPS_USERS: Lazy<Mutex<FxHashMap<PpsPrivateUser48, PpsUserInfo>>> =
-    Lazy::new(|| Default::default());
-pub static PPS_NEXT_USER_ID: Mutex<PpsPublicUser> = Mutex::new(PpsPublicUser { id: 1 });
-pub static PPS_USERS_DISABLED_UNTIIL: Mutex<Option<Instant>> = Mutex::new(None);
-#[derive(Hash, PartialEq, Eq, Clone, Copy)]
-pub struct PpsPrivateUser48 {
-    /// First 3 ipv6 segements
-    prefix: [u16; 3],
-}
+/// Latest snapshot published by the canvas processor
+static PPS_USERS_SNAPSHOT: Lazy<ArcSwap<PpsUsersSnapshot>> = Lazy::new(Default::default);
+/// Secret key to derive public user ids from prefixes
+static PPS_USER_ID_KEY: OnceCell<[u8; 16]> = OnceCell::new();
+
+/// Prefix lengths users can be tracked at (coarsest first)
+pub const PREFIX_LENS: [u8; 5] = [32, 40, 48, 56, 64];
+/// Publish changes to the users at most this often
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(200);
+/// Users seen more recently than this are never evicted to make room for new ones
+const EVICTION_MIN_IDLE: Duration = Duration::from_secs(60);
+/// When all users are tracked, 1/16 of max_tracked_users gets evicted at once
+const EVICTION_BATCH_DIVISOR: usize = 16;
 
-impl PpsPrivateUser48 {
-    pub fn from_addr(user_address: Ipv6Addr) -> Self {
-        let s = user_address.segments();
+impl PpsPublicUser {
+    /// Derive the id from a prefix using the secret key.
+    /// Ids are 48 bit (to be safe for JavaScript numbers) and never 0.
+    pub fn from_prefix(address: Ipv6Addr, prefix_len: u8) -> Self {
+        let key = PPS_USER_ID_KEY
+            .get()
+            .expect("The user id key should be set on startup!");
+        let mut hasher = SipHasher24::new_with_key(key);
+        // Length first so a /48 and /64 never get the same id by design
+        hasher.write_u8(prefix_len);
+        hasher.write(&address.octets()[..prefix_len as usize / 8]);
         Self {
-            prefix: [s[0], s[1], s[2]],
+            id: (hasher.finish() & 0xFFFF_FFFF_FFFF).max(1),
         }
     }
 }
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy)]
-pub struct PpsPrivateUser64 {
-    /// First 4 ipv6 segements
-    prefix: [u16; 4],
+/// Thresholds which limit how many users get tracked (see cli_args.rs).
+/// Kept in SERVER_CONFIG, where admins can change them (/admin/config).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct TrackingOptions {
+    /// A prefix gets tracked as one user as soon as it has more sub prefixes
+    /// than this (/40s in a /32, /48s in a /40, /56s in a /48 and /64s in a /56)
+    pub max_fanout: [usize; 4],
+    /// When this many users are tracked, the least recently seen ones get evicted
+    /// for new ones (if they were idle for EVICTION_MIN_IDLE and aren't on cooldown)
+    pub max_tracked_users: usize,
+    /// Forget users which weren't seen for this long
+    #[serde(
+        rename = "user_timeout_secs",
+        serialize_with = "serialize_secs",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub user_timeout: Duration,
+    /// How often to look for users to forget
+    #[serde(
+        rename = "cleanup_interval_secs",
+        serialize_with = "serialize_secs",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub cleanup_interval: Duration,
+}
+
+fn serialize_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+impl TrackingOptions {
+    pub fn validate(&self) -> Result<()> {
+        // Each level of the trie branches on 8 bits, so there are never more than 256 sub prefixes
+        ensure!(
+            self.max_fanout
+                .iter()
+                .all(|max_fanout| (1..=256).contains(max_fanout)),
+            "The max fanout of each prefix length has to be between 1 and 256"
+        );
+        ensure!(
+            self.max_tracked_users > 0,
+            "At least one user has to be trackable"
+        );
+        ensure!(
+            !self.user_timeout.is_zero() && !self.cleanup_interval.is_zero(),
+            "The user timeout and cleanup interval have to be positive"
+        );
+        ensure!(
+            self.cleanup_interval <= self.user_timeout,
+            "The cleanup interval can't be longer than the user timeout"
+        );
+        Ok(())
+    }
+}
+
+/// Users are tracked per /64 in a trie of prefixes. Prefixes with a lot of
+/// sub prefixes (people using or spoofing a lot of addresses) get collapsed
+/// and are tracked as a single user instead. Everyone else isn't affected.
+pub struct PpsUsers {
+    options: TrackingOptions,
+    /// Keyed by the first 32 bits
+    roots: FxHashMap<[u8; 4], PrefixNode>,
+    /// Amount of tracked users (PrefixNode::User)
+    user_count: usize,
+    /// How many prefixes got collapsed into one user (per depth in PREFIX_LENS)
+    collapsed_prefixes: [u64; 4],
+    /// Users which weren't tracked since max_tracked_users was reached and nobody could be evicted
+    rejected_users: u64,
+    /// Users which were evicted to make room for new ones
+    evicted_users: u64,
+    /// Shared by all sources which aren't tracked (rate limit and cooldown),
+    /// so they can still draw without getting around the limits
+    untracked: PpsUserInfoData,
+    /// Pixels of untracked sources dropped by the rate limit or cooldown
+    untracked_dropped: u64,
+    /// Nobody can be evicted before this (known from the last eviction which found nobody)
+    next_eviction_at: Option<Instant>,
+    last_cleaned_at: Instant,
+    /// Whether anything readers care about changed since the last snapshot
+    changed: bool,
+    last_snapshot_at: Option<Instant>,
+}
+
+/// What lookups from other threads can see about the users
+#[derive(Default)]
+pub struct PpsUsersSnapshot {
+    /// Keyed by the prefix by which the user is tracked
+    users: FxHashMap<Ipv6Net, PpsUserSnapshot>,
+    metrics: PpsTrackingMetrics,
+}
+
+/// How full the user tracking is (served as /per_user_pps_metrics.json)
+#[derive(Serialize, Clone, Default)]
+pub struct PpsTrackingMetrics {
+    pub tracked_users: usize,
+    pub max_tracked_users: usize,
+    /// Currently tracked users per prefix length (64 are individual users)
+    pub tracked_users_by_prefix_len: BTreeMap<u8, usize>,
+    /// How often prefixes of each length got collapsed into one user (since startup)
+    pub collapsed_prefixes_by_prefix_len: BTreeMap<u8, u64>,
+    /// Users which weren't tracked since max_tracked_users was reached and all
+    /// tracked users were active recently (since startup)
+    pub rejected_users: u64,
+    /// Users which were forgotten early to make room for new ones (since startup)
+    pub evicted_users: u64,
+    /// Pixels of untracked sources which were dropped by their shared rate limit or cooldown (since startup)
+    pub untracked_dropped: u64,
+}
+
+/// Metrics of the latest snapshot
+pub fn metrics() -> PpsTrackingMetrics {
+    PPS_USERS_SNAPSHOT.load().metrics.clone()
+}
+
+#[derive(Clone, Copy)]
+pub struct PpsUserSnapshot {
+    pub user_id: PpsPublicUser,
+    cooldown_until: Option<Instant>,
+}
+
+impl PpsUserSnapshot {
+    /// Time left until this user can place another pixel (None if not on cooldown)
+    pub fn cooldown_remaining(&self, now: Instant) -> Option<Duration> {
+        self.cooldown_until
+            .filter(|cooldown_until| *cooldown_until > now)
+            .map(|cooldown_until| cooldown_until - now)
+    }
+}
+
+/// Find the user of an address in the latest snapshot
+pub fn find_user(user_ip: Ipv6Addr) -> Option<PpsUserSnapshot> {
+    let snapshot = PPS_USERS_SNAPSHOT.load();
+    PREFIX_LENS.iter().rev().find_map(|prefix_len| {
+        let prefix = Ipv6Net::new(user_ip, *prefix_len).ok()?.trunc();
+        snapshot.users.get(&prefix).copied()
+    })
 }
 
-impl PpsPrivateUser64 {
-    pub fn from_addr(user_address: Ipv6Addr) -> Self {
-        let s = user_address.segments();
+impl PpsUsers {
+    /// Apply changed thresholds. Users which are tracked already are kept
+    /// until new users need room (even if there are more than max_tracked_users now).
+    pub fn set_options(&mut self, options: TrackingOptions) {
+        self.options = options;
+    }
+
+    pub fn new(options: TrackingOptions, now: Instant) -> Self {
         Self {
-            prefix: [s[0], s[1], s[2], s[3]],
+            options,
+            roots: FxHashMap::default(),
+            user_count: 0,
+            collapsed_prefixes: [0; 4],
+            rejected_users: 0,
+            evicted_users: 0,
+            untracked: PpsUserInfoData::new(Ipv6Addr::UNSPECIFIED, 0, now),
+            untracked_dropped: 0,
+            next_eviction_at: None,
+            last_cleaned_at: now,
+            changed: true,
+            last_snapshot_at: None,
+        }
+    }
+
+    /// Let readers know about a change which doesn't add or remove users (e.g. a started cooldown)
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+
+    /// Publish a new snapshot if anything changed and the last one isn't too recent
+    pub fn publish_snapshot_if_due(&mut self, now: Instant) {
+        if !self.changed
+            || self
+                .last_snapshot_at
+                .map(|at| now.saturating_duration_since(at) < SNAPSHOT_INTERVAL)
+                .unwrap_or(false)
+        {
+            return;
+        }
+        let mut users = FxHashMap::default();
+        users.reserve(self.user_count);
+        let mut tracked_users_by_prefix_len: BTreeMap<u8, usize> = PREFIX_LENS
+            .iter()
+            .map(|prefix_len| (*prefix_len, 0))
+            .collect();
+        for root in self.roots.values() {
+            root.for_each_user(&mut |data| {
+                *tracked_users_by_prefix_len
+                    .entry(data.prefix.prefix_len())
+                    .or_default() += 1;
+                users.insert(
+                    data.prefix,
+                    PpsUserSnapshot {
+                        user_id: data.user_id,
+                        cooldown_until: data.cooldown_until,
+                    },
+                );
+            });
         }
+        let metrics = PpsTrackingMetrics {
+            tracked_users: self.user_count,
+            max_tracked_users: self.options.max_tracked_users,
+            tracked_users_by_prefix_len,
+            collapsed_prefixes_by_prefix_len: PREFIX_LENS
+                .iter()
+                .zip(self.collapsed_prefixes)
+                .map(|(prefix_len, count)| (*prefix_len, count))
+                .collect(),
+            rejected_users: self.rejected_users,
+            evicted_users: self.evicted_users,
+            untracked_dropped: self.untracked_dropped,
+        };
+        PPS_USERS_SNAPSHOT.store(Arc::new(PpsUsersSnapshot { users, metrics }));
+        self.changed = false;
+        self.last_snapshot_at = Some(now);
+    }
+
+    /// Update the last seen time of the user of the address (adding it if there is room)
+    fn touch(&mut self, user_ip: Ipv6Addr, now: Instant) -> Touched {
+        let can_add = self.user_count < self.options.max_tracked_users;
+        let key = root_key(&user_ip.octets());
+        match self.roots.get_mut(&key) {
+            Some(root) => root.touch(user_ip, 0, now, can_add, &self.options.max_fanout),
+            None if can_add => {
+                self.roots
+                    .insert(key, PrefixNode::new_path(user_ip, 0, now));
+                Touched {
+                    user_count_change: 1,
+                    ..Default::default()
+                }
+            }
+            None => Touched {
+                rejected: true,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Forget the least recently seen users to make room for new ones. Only users which
+    /// were idle for EVICTION_MIN_IDLE and aren't on cooldown can be evicted, so
+    /// flooding the table with new sources can't reset the limits of active users.
+    /// Returns whether there is room now.
+    fn evict_least_recently_seen(&mut self, now: Instant) -> bool {
+        if self.next_eviction_at.map(|at| now < at).unwrap_or(false) {
+            return false;
+        }
+        let Some(idle_cutoff) = now.checked_sub(EVICTION_MIN_IDLE) else {
+            return false;
+        };
+        let mut evictable = Vec::new();
+        let mut next_eviction_at: Option<Instant> = None;
+        for root in self.roots.values() {
+            root.for_each_user(&mut |data| {
+                if is_evictable(data, idle_cutoff, now) {
+                    evictable.push(data.last_seen);
+                } else {
+                    let evictable_at = (data.last_seen + EVICTION_MIN_IDLE)
+                        .max(data.cooldown_until.unwrap_or(now));
+                    next_eviction_at =
+                        Some(next_eviction_at.map_or(evictable_at, |at| at.min(evictable_at)));
+                }
+            });
+        }
+        if evictable.is_empty() {
+            self.next_eviction_at = next_eviction_at;
+            return false;
+        }
+        let to_evict = (self.user_count + 1).saturating_sub(self.options.max_tracked_users)
+            + self.options.max_tracked_users / EVICTION_BATCH_DIVISOR;
+        let cutoff = if evictable.len() > to_evict {
+            *evictable.select_nth_unstable(to_evict - 1).1
+        } else {
+            idle_cutoff
+        };
+        self.roots
+            .retain(|_, root| root.retain_users(&mut |data| !is_evictable(data, cutoff, now)));
+        let user_count = self.roots.values().map(PrefixNode::user_count).sum();
+        let evicted = self.user_count.saturating_sub(user_count);
+        debug!("Evicted {evicted} users to make room for new ones");
+        self.evicted_users += evicted as u64;
+        self.user_count = user_count;
+        self.changed = true;
+        self.user_count < self.options.max_tracked_users
     }
 }
 
-pub enum PpsUserInfo {
-    User64 {
-        data_map: FxHashMap<PpsPrivateUser64, PpsUserInfoData>,
-    },
-    User48 {
-        data: PpsUserInfoData,
-    },
+/// Whether the user wasn't seen after cutoff and isn't on cooldown
+fn is_evictable(data: &PpsUserInfoData, cutoff: Instant, now: Instant) -> bool {
+    data.last_seen <= cutoff && data.cooldown_remaining(now).is_none()
+}
+
+enum PrefixNode {
+    /// Tracked as one user
+    User(PpsUserInfoData),
+    /// Keyed by the next 8 bits
+    Split(FxHashMap<u8, PrefixNode>),
+}
+
+/// What happened when updating the activity of a user
+#[derive(Default)]
+struct Touched {
+    /// Change of the amount of tracked users
+    user_count_change: isize,
+    /// Depth (index in PREFIX_LENS) of the prefix which got collapsed (if any)
+    collapsed_at: Option<usize>,
+    /// The user wasn't tracked since too many users are tracked already
+    rejected: bool,
+}
+
+/// The first 32 bits of an address
+fn root_key(octets: &[u8; 16]) -> [u8; 4] {
+    [octets[0], octets[1], octets[2], octets[3]]
+}
+
+impl PrefixNode {
+    /// Node at depth (index in PREFIX_LENS) with only the /64 of the address below it
+    fn new_path(address: Ipv6Addr, depth: usize, now: Instant) -> Self {
+        if depth == PREFIX_LENS.len() - 1 {
+            return PrefixNode::User(PpsUserInfoData::new(address, PREFIX_LENS[depth], now));
+        }
+        let mut children = FxHashMap::default();
+        children.insert(
+            address.octets()[4 + depth],
+            PrefixNode::new_path(address, depth + 1, now),
+        );
+        PrefixNode::Split(children)
+    }
+
+    fn for_each_user(&self, f: &mut impl FnMut(&PpsUserInfoData)) {
+        match self {
+            PrefixNode::User(data) => f(data),
+            PrefixNode::Split(children) => {
+                children.values().for_each(|child| child.for_each_user(f))
+            }
+        }
+    }
+
+    fn for_each_user_mut(&mut self, f: &mut impl FnMut(&mut PpsUserInfoData)) {
+        match self {
+            PrefixNode::User(data) => f(data),
+            PrefixNode::Split(children) => children
+                .values_mut()
+                .for_each(|child| child.for_each_user_mut(f)),
+        }
+    }
+
+    fn user_count(&self) -> usize {
+        let mut count = 0;
+        self.for_each_user(&mut |_| count += 1);
+        count
+    }
+
+    /// One user for the whole prefix which continues the counters and limits of all users below
+    fn collapsed(&self, address: Ipv6Addr, depth: usize, now: Instant) -> PpsUserInfoData {
+        let mut collapsed = PpsUserInfoData::new(address, PREFIX_LENS[depth], now);
+        self.for_each_user(&mut |data| {
+            collapsed.pps_counter += data.pps_counter;
+            collapsed.dropped_counter += data.dropped_counter;
+            collapsed.rate_limit_tokens = collapsed.rate_limit_tokens.min(data.rate_limit_tokens);
+            collapsed.cooldown_until = collapsed.cooldown_until.max(data.cooldown_until);
+        });
+        collapsed
+    }
+
+    /// Update the last seen time of the user of the address (adding it if can_add)
+    fn touch(
+        &mut self,
+        address: Ipv6Addr,
+        depth: usize,
+        now: Instant,
+        can_add: bool,
+        max_fanout: &[usize; 4],
+    ) -> Touched {
+        let PrefixNode::Split(children) = self else {
+            if let PrefixNode::User(data) = self {
+                data.last_seen = now;
+            }
+            return Touched::default();
+        };
+        let key = address.octets()[4 + depth];
+        if let Some(child) = children.get_mut(&key) {
+            return child.touch(address, depth + 1, now, can_add, max_fanout);
+        }
+        if children.len() < max_fanout[depth] {
+            if !can_add {
+                return Touched {
+                    rejected: true,
+                    ..Default::default()
+                };
+            }
+            children.insert(key, PrefixNode::new_path(address, depth + 1, now));
+            return Touched {
+                user_count_change: 1,
+                ..Default::default()
+            };
+        }
+        // Too many sub prefixes. Track them as one user from now on.
+        let removed_users = self.user_count();
+        let collapsed = self.collapsed(address, depth, now);
+        debug!(
+            "Tracking {removed_users} users in /{} as one user {}",
+            PREFIX_LENS[depth], collapsed.user_id.id
+        );
+        *self = PrefixNode::User(collapsed);
+        Touched {
+            user_count_change: 1 - removed_users as isize,
+            collapsed_at: Some(depth),
+            rejected: false,
+        }
+    }
+
+    fn find(&self, address: Ipv6Addr, depth: usize) -> Option<&PpsUserInfoData> {
+        match self {
+            PrefixNode::User(data) => Some(data),
+            PrefixNode::Split(children) => children
+                .get(&address.octets()[4 + depth])?
+                .find(address, depth + 1),
+        }
+    }
+
+    fn find_mut(&mut self, address: Ipv6Addr, depth: usize) -> Option<&mut PpsUserInfoData> {
+        match self {
+            PrefixNode::User(data) => Some(data),
+            PrefixNode::Split(children) => children
+                .get_mut(&address.octets()[4 + depth])?
+                .find_mut(address, depth + 1),
+        }
+    }
+
+    /// Remove users for which keep returns false. Returns false if nothing is left.
+    fn retain_users(&mut self, keep: &mut impl FnMut(&PpsUserInfoData) -> bool) -> bool {
+        match self {
+            PrefixNode::User(data) => keep(data),
+            PrefixNode::Split(children) => {
+                children.retain(|_, child| child.retain_users(keep));
+                !children.is_empty()
+            }
+        }
+    }
+}
+
+/// Read the secret key for user ids from the file or create it with a random key.
+/// Must be called once before tracking any users.
+pub fn load_or_create_user_id_key(path: &Path) -> Result<()> {
+    let key = if path.exists() {
+        let hex = std::fs::read_to_string(path).with_context(|| format!("Reading {path:?}"))?;
+        let hex = hex.trim();
+        ensure!(
+            hex.len() == 32 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            "{path:?} should contain a 128 bit key as 32 hex digits"
+        );
+        u128::from_str_radix(hex, 16)?.to_be_bytes()
+    } else {
+        let mut key = [0u8; 16];
+        getrandom::getrandom(&mut key).context("Generating user id key")?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // Only readable by the owner, since anyone with the key can link user ids to prefixes
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| {
+                file.write_all(format!("{:032x}\n", u128::from_be_bytes(key)).as_bytes())
+            })
+            .with_context(|| format!("Writing {path:?}"))?;
+        info!("Generated new user id key in {path:?}");
+        key
+    };
+    PPS_USER_ID_KEY.set(key).ok();
+    Ok(())
 }
 
 pub struct PpsUserInfoData {
     last_seen: Instant,
     user_id: PpsPublicUser,
+    /// Prefix by which the user is tracked
+    prefix: Ipv6Net,
     pub pps_counter: usize,
+    /// Pixels dropped by the rate limit
+    pub dropped_counter: usize,
+    rate_limit_tokens: f64,
+    rate_limit_refilled_at: Instant,
+    /// Until when this user can't place another pixel (cooldown game mode)
+    cooldown_until: Option<Instant>,
 }
 
 impl PpsUserInfoData {
+    fn new(address: Ipv6Addr, prefix_len: u8, now: Instant) -> Self {
+        Self {
+            last_seen: now,
+            user_id: PpsPublicUser::from_prefix(address, prefix_len),
+            prefix: Ipv6Net::new(address, prefix_len)
+                .expect("Prefix lengths should be valid")
+                .trunc(),
+            pps_counter: 0,
+            dropped_counter: 0,
+            // Bucket starts full (gets capped to the burst on first use)
+            rate_limit_tokens: f64::MAX,
+            rate_limit_refilled_at: now,
+            cooldown_until: None,
+        }
+    }
+
     pub fn get_user_id(&self) -> PpsPublicUser {
         self.user_id
     }
-}
 
-pub fn is_disabled(
-    pps_users: &mut FxHashMap<PpsPrivateUser48, PpsUserInfo>,
-    pps_users_disabled_until: &mut Option<Instant>,
-    now: Instant,
-) -> bool {
-    // Temporarily disabled because someone keeps fucking with a lot of IPs or spoofing them!
-    if let Some(disabled_until) = pps_users_disabled_until {
-        if *disabled_until > now {
-            true
-        } else {
-            *pps_users_disabled_until = None;
-            false
-        }
-    } else {
-        if pps_users.len() >= 1024 {
-            // Someone is fucking with a lot of IPs or spoofs them. Disabling for 30 seconds!
-            pps_users.clear();
-            *pps_users_disabled_until = Some(now + Duration::from_secs(30));
+    /// Take a token from this users bucket. Returns false if the user exceeds the rate limit.
+    pub fn try_take_rate_limit_token(&mut self, rate_limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.rate_limit_refilled_at);
+        self.rate_limit_tokens = (self.rate_limit_tokens
+            + elapsed.as_secs_f64() * rate_limit.pixels_per_second)
+            .min(rate_limit.burst);
+        self.rate_limit_refilled_at = now;
+        if self.rate_limit_tokens >= 1.0 {
+            self.rate_limit_tokens -= 1.0;
             true
         } else {
             false
         }
     }
+
+    /// Start the cooldown (after the user placed a pixel)
+    pub fn start_cooldown(&mut self, cooldown: Duration, now: Instant) {
+        self.cooldown_until = Some(now + cooldown);
+    }
+
+    /// Time left until this user can place another pixel (None if not on cooldown)
+    pub fn cooldown_remaining(&self, now: Instant) -> Option<Duration> {
+        self.cooldown_until
+            .filter(|cooldown_until| *cooldown_until > now)
+            .map(|cooldown_until| cooldown_until - now)
+    }
+}
+
+/// Limits which get enforced on each user
+#[derive(Clone, Copy, Default)]
+pub struct UserLimits {
+    pub rate_limit: Option<RateLimit>,
+    /// Only one pixel can be placed per cooldown (cooldown game mode)
+    pub pixel_cooldown: Option<Duration>,
+}
+
+/// Token bucket limit of how many pixels a user can draw
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    /// How many tokens get refilled per second
+    pub pixels_per_second: f64,
+    /// Max size of the bucket
+    pub burst: f64,
+}
+
+/// Counters of a user since the last reset
+pub struct PpsCounters {
+    /// Prefix by which the user is tracked (never sent to public clients)
+    pub prefix: Ipv6Net,
+    pub pps: usize,
+    pub dropped: usize,
 }
 
 pub fn ensure_existing_activity_updated_and_migrated(
-    pps_users: &mut FxHashMap<PpsPrivateUser48, PpsUserInfo>,
-    next_user_id: &mut PpsPublicUser,
+    pps_users: &mut PpsUsers,
     now: Instant,
     user_ip: Ipv6Addr,
 ) {
-    if pps_users.len() >= 1024 {
-        // Someone is fucking with a lot of IPs or spoofs them. Do not add any further entries!
-        return;
+    let mut touched = pps_users.touch(user_ip, now);
+    if touched.rejected && pps_users.evict_least_recently_seen(now) {
+        touched = pps_users.touch(user_ip, now);
     }
-
-    let user_48 = PpsPrivateUser48::from_addr(user_ip);
-
-    let user_info = pps_users.entry(user_48).or_insert_with(|| {
-        let new_user_id = *next_user_id;
-        *next_user_id = PpsPublicUser {
-            id: new_user_id.id + 1,
-        };
-        let user_64 = PpsPrivateUser64::from_addr(user_ip);
-
-        let mut data_map = FxHashMap::default();
-        data_map.insert(
-            user_64,
-            PpsUserInfoData {
-                last_seen: now,
-                user_id: new_user_id,
-                pps_counter: 0,
-            },
-        );
-        PpsUserInfo::User64 { data_map }
-    });
-
-    let migrate_to_user48_info = match user_info {
-        PpsUserInfo::User48 { data } => {
-            data.last_seen = now;
-            None
-        }
-        PpsUserInfo::User64 { data_map } => {
-            if data_map.len() < 1024 {
-                let user_64 = PpsPrivateUser64::from_addr(user_ip);
-                let data = data_map.entry(user_64).or_insert_with(|| {
-                    let new_user_id = *next_user_id;
-                    *next_user_id = PpsPublicUser {
-                        id: new_user_id.id + 1,
-                    };
-                    PpsUserInfoData {
-                        last_seen: now,
-                        user_id: new_user_id,
-                        pps_counter: 0,
-                    }
-                });
-                data.last_seen = now;
-                None
-            } else {
-                Some(data_map.values().min_by_key(|data| data.user_id.id).expect(
-                    "PpsUserInfo::User64.data_map should never be empty because of earlier check!",
-                ).to_owned())
-            }
-        }
-    };
-
-    if let Some(user48_data) = migrate_to_user48_info {
-        // Migrate User64 to User48
-        let user48_migration_info = PpsUserInfoData {
-            last_seen: now,
-            user_id: user48_data.user_id.clone(),
-            pps_counter: 0,
-        };
-
-        // Not returned from above means the user was User64 and had to many entries. Migrate from User64 to User48 first
-        let new_user_info = PpsUserInfo::User48 {
-            data: user48_migration_info,
-        };
-        pps_users.insert(user_48, new_user_info);
+    if let Some(depth) = touched.collapsed_at {
+        pps_users.collapsed_prefixes[depth] += 1;
+    }
+    if touched.rejected {
+        pps_users.rejected_users += 1;
+        pps_users.changed = true;
+    }
+    if touched.user_count_change != 0 {
+        pps_users.user_count = pps_users
+            .user_count
+            .saturating_add_signed(touched.user_count_change);
+        pps_users.changed = true;
     }
 }
 
-pub fn cleanup(pps_users: &mut FxHashMap<PpsPrivateUser48, PpsUserInfo>, now: Instant) {
-    pps_users.retain(|_, user_info| match user_info {
-        PpsUserInfo::User48 { data } => now - data.last_seen < Duration::from_secs(60 * 60),
-        PpsUserInfo::User64 { data_map } => {
-            data_map.retain(|_, data| now - data.last_seen < Duration::from_secs(60 * 60));
-            data_map.len() > 0
-        }
-    });
+/// Forget users which weren't seen for a while (if the cleanup interval passed)
+pub fn cleanup_if_due(pps_users: &mut PpsUsers, now: Instant) {
+    if now.saturating_duration_since(pps_users.last_cleaned_at) < pps_users.options.cleanup_interval
+    {
+        return;
+    }
+    pps_users.last_cleaned_at = now;
+    let Some(seen_cutoff) = now.checked_sub(pps_users.options.user_timeout) else {
+        return;
+    };
+    pps_users
+        .roots
+        .retain(|_, root| root.retain_users(&mut |data| data.last_seen > seen_cutoff));
+    pps_users.user_count = pps_users.roots.values().map(PrefixNode::user_count).sum();
+    pps_users.changed = true;
 }
 
 pub fn get_all_pps_counters_and_reset(
-    pps_users: &mut FxHashMap<PpsPrivateUser48, PpsUserInfo>,
-) -> FxHashMap<PpsPublicUser, usize> {
+    pps_users: &mut PpsUsers,
+) -> FxHashMap<PpsPublicUser, PpsCounters> {
     let mut map = FxHashMap::default();
-    for user_info in pps_users.values_mut() {
-        match user_info {
-            PpsUserInfo::User48 { data } => {
-                map.insert(data.user_id, data.pps_counter);
-                data.pps_counter = 0;
-            }
-            PpsUserInfo::User64 { data_map } => {
-                for data in data_map.values_mut() {
-                    map.insert(data.user_id, data.pps_counter);
-                    data.pps_counter = 0;
-                }
-            }
-        }
+    for root in pps_users.roots.values_mut() {
+        root.for_each_user_mut(&mut |data| {
+            map.insert(
+                data.user_id,
+                PpsCounters {
+                    prefix: data.prefix,
+                    pps: data.pps_counter,
+                    dropped: data.dropped_counter,
+                },
+            );
+            data.pps_counter = 0;
+            data.dropped_counter = 0;
+        });
     }
     map
 }
 
-pub fn find_user_info_data<'a>(
-    pps_users: &'a FxHashMap<PpsPrivateUser48, PpsUserInfo>,
-    user_ip: Ipv6Addr,
-) -> Option<&'a PpsUserInfoData> {
+fn find_user_info_data(pps_users: &PpsUsers, user_ip: Ipv6Addr) -> Option<&PpsUserInfoData> {
     pps_users
-        .get(&PpsPrivateUser48::from_addr(user_ip))
-        .and_then(|info| match info {
-            PpsUserInfo::User48 { data } => Some(data),
-            PpsUserInfo::User64 { data_map } => data_map.get(&PpsPrivateUser64::from_addr(user_ip)),
-        })
+        .roots
+        .get(&root_key(&user_ip.octets()))?
+        .find(user_ip, 0)
 }
 
-pub fn find_user_info_data_mut<'a>(
-    pps_users: &'a mut FxHashMap<PpsPrivateUser48, PpsUserInfo>,
+/// Create a cooldown event for the user with the given address (if it is tracked and on cooldown)
+pub fn cooldown_event(
+    pps_users: &PpsUsers,
     user_ip: Ipv6Addr,
-) -> Option<&'a mut PpsUserInfoData> {
-    pps_users
-        .get_mut(&PpsPrivateUser48::from_addr(user_ip))
-        .and_then(|info| match info {
-            PpsUserInfo::User48 { data } => Some(data),
-            PpsUserInfo::User64 { data_map } => {
-                data_map.get_mut(&PpsPrivateUser64::from_addr(user_ip))
+    now: Instant,
+) -> Option<CooldownEvent> {
+    let data = find_user_info_data(pps_users, user_ip)?;
+    Some(CooldownEvent {
+        user_id: data.user_id.id,
+        user_prefix: data.prefix,
+        remaining: data.cooldown_remaining(now)?,
+    })
+}
+
+/// Counters of all untracked sources together since the last reset
+pub fn get_untracked_pps_counters_and_reset(pps_users: &mut PpsUsers) -> PpsCounters {
+    let untracked = &mut pps_users.untracked;
+    let counters = PpsCounters {
+        prefix: untracked.prefix,
+        pps: untracked.pps_counter,
+        dropped: untracked.dropped_counter,
+    };
+    untracked.pps_counter = 0;
+    untracked.dropped_counter = 0;
+    if counters.dropped > 0 {
+        pps_users.untracked_dropped += counters.dropped as u64;
+        pps_users.changed = true;
+    }
+    counters
+}
+
+/// The user of the address or, if it isn't tracked, the one shared by all untracked sources.
+/// The bool is true for tracked users.
+pub fn find_user_info_data_or_untracked_mut(
+    pps_users: &mut PpsUsers,
+    user_ip: Ipv6Addr,
+) -> (bool, &mut PpsUserInfoData) {
+    match pps_users
+        .roots
+        .get_mut(&root_key(&user_ip.octets()))
+        .and_then(|root| root.find_mut(user_ip, 0))
+    {
+        Some(data) => (true, data),
+        None => (false, &mut pps_users.untracked),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn options(max_fanout: [usize; 4], max_tracked_users: usize) -> TrackingOptions {
+        TrackingOptions {
+            max_fanout,
+            max_tracked_users,
+            user_timeout: MINUTE * 10,
+            cleanup_interval: MINUTE,
+        }
+    }
+
+    fn users(options: TrackingOptions, now: Instant) -> PpsUsers {
+        PPS_USER_ID_KEY.get_or_init(|| [7; 16]);
+        PpsUsers::new(options, now)
+    }
+
+    /// Address in 2001:db8:<third>:<fourth>::<host>, so fourth's low byte picks the /64 in a /56
+    fn address(third: u16, fourth: u16, host: u16) -> Ipv6Addr {
+        Ipv6Addr::new(0x2001, 0xdb8, third, fourth, 0, 0, 0, host)
+    }
+
+    fn is_tracked(pps_users: &mut PpsUsers, user_ip: Ipv6Addr) -> bool {
+        find_user_info_data_or_untracked_mut(pps_users, user_ip).0
+    }
+
+    fn tracked_prefix(pps_users: &PpsUsers, user_ip: Ipv6Addr) -> Option<Ipv6Net> {
+        find_user_info_data(pps_users, user_ip).map(|data| data.prefix)
+    }
+
+    #[test]
+    fn user_ids_depend_on_the_prefix() {
+        PPS_USER_ID_KEY.get_or_init(|| [7; 16]);
+        let a = PpsPublicUser::from_prefix(address(1, 1, 1), 64);
+        assert!(a == PpsPublicUser::from_prefix(address(1, 1, 2), 64));
+        assert!(a != PpsPublicUser::from_prefix(address(1, 2, 1), 64));
+        // Same bits, different prefix length
+        assert!(
+            PpsPublicUser::from_prefix(address(0, 0, 1), 48)
+                != PpsPublicUser::from_prefix(address(0, 0, 1), 64)
+        );
+        assert!(a.id != 0 && a.id <= 0xFFFF_FFFF_FFFF);
+    }
+
+    #[test]
+    fn tracks_each_64_as_one_user() {
+        let now = Instant::now();
+        let mut pps_users = users(options([256; 4], 100), now);
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, now, address(1, 1, 1));
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, now, address(1, 1, 2));
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, now, address(1, 2, 1));
+        assert_eq!(pps_users.user_count, 2);
+        assert_eq!(
+            tracked_prefix(&pps_users, address(1, 1, 3)),
+            Some("2001:db8:1:1::/64".parse().unwrap())
+        );
+        assert_eq!(tracked_prefix(&pps_users, address(1, 3, 1)), None);
+    }
+
+    #[test]
+    fn collapses_prefixes_with_too_many_sub_prefixes() {
+        let now = Instant::now();
+        let mut pps_users = users(options([256, 256, 256, 2], 100), now);
+        for fourth in 0..2 {
+            let user_ip = address(1, fourth, 1);
+            ensure_existing_activity_updated_and_migrated(&mut pps_users, now, user_ip);
+            find_user_info_data_or_untracked_mut(&mut pps_users, user_ip)
+                .1
+                .pps_counter += 5;
+        }
+        assert_eq!(pps_users.user_count, 2);
+
+        // A third /64 in the same /56
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, now, address(1, 2, 1));
+        assert_eq!(pps_users.user_count, 1);
+        assert_eq!(pps_users.collapsed_prefixes, [0, 0, 0, 1]);
+        let (is_tracked, collapsed) =
+            find_user_info_data_or_untracked_mut(&mut pps_users, address(1, 0xFF, 1));
+        assert!(is_tracked);
+        assert_eq!(collapsed.prefix, "2001:db8:1::/56".parse().unwrap());
+        // The counters of the collapsed users are kept
+        assert_eq!(collapsed.pps_counter, 10);
+
+        // Other /56s aren't affected
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, now, address(2, 0, 1));
+        assert_eq!(
+            tracked_prefix(&pps_users, address(2, 0, 1)),
+            Some("2001:db8:2::/64".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn max_fanout_of_256_never_collapses() {
+        let now = Instant::now();
+        let mut pps_users = users(options([256; 4], 1000), now);
+        for fourth in 0..256 {
+            ensure_existing_activity_updated_and_migrated(
+                &mut pps_users,
+                now,
+                address(1, fourth, 1),
+            );
+        }
+        assert_eq!(pps_users.user_count, 256);
+        assert_eq!(pps_users.collapsed_prefixes, [0; 4]);
+
+        let mut invalid = options([256; 4], 1000);
+        invalid.max_fanout[2] = 257;
+        assert!(invalid.validate().is_err());
+        invalid.max_fanout[2] = 0;
+        assert!(invalid.validate().is_err());
+        assert!(options([1, 16, 256, 256], 1).validate().is_ok());
+    }
+
+    #[test]
+    fn forgets_users_after_the_timeout() {
+        let start = Instant::now();
+        let mut pps_users = users(options([256; 4], 100), start);
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, start, address(1, 1, 1));
+        let later = start + MINUTE * 6;
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, later, address(1, 2, 1));
+
+        cleanup_if_due(&mut pps_users, start + MINUTE * 11);
+        assert_eq!(pps_users.user_count, 1);
+        assert!(!is_tracked(&mut pps_users, address(1, 1, 1)));
+        assert!(is_tracked(&mut pps_users, address(1, 2, 1)));
+
+        cleanup_if_due(&mut pps_users, start + MINUTE * 17);
+        assert_eq!(pps_users.user_count, 0);
+        assert!(pps_users.roots.is_empty());
+    }
+
+    #[test]
+    fn evicts_least_recently_seen_users_when_full() {
+        let start = Instant::now();
+        let mut pps_users = users(options([256; 4], 2), start);
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, start, address(1, 1, 1));
+        ensure_existing_activity_updated_and_migrated(
+            &mut pps_users,
+            start + Duration::from_secs(1),
+            address(1, 2, 1),
+        );
+
+        // Everyone was active recently, so new users share the untracked limits
+        let soon = start + Duration::from_secs(30);
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, soon, address(1, 3, 1));
+        assert!(!is_tracked(&mut pps_users, address(1, 3, 1)));
+        assert_eq!(pps_users.rejected_users, 1);
+
+        let later = start + MINUTE * 2;
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, later, address(1, 3, 1));
+        assert!(is_tracked(&mut pps_users, address(1, 3, 1)));
+        assert!(!is_tracked(&mut pps_users, address(1, 1, 1)));
+        assert!(is_tracked(&mut pps_users, address(1, 2, 1)));
+        assert_eq!(pps_users.evicted_users, 1);
+        assert_eq!(pps_users.user_count, 2);
+    }
+
+    #[test]
+    fn users_on_cooldown_are_not_evicted() {
+        let start = Instant::now();
+        let mut pps_users = users(options([256; 4], 1), start);
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, start, address(1, 1, 1));
+        find_user_info_data_or_untracked_mut(&mut pps_users, address(1, 1, 1))
+            .1
+            .start_cooldown(MINUTE * 5, start);
+
+        let later = start + MINUTE * 2;
+        ensure_existing_activity_updated_and_migrated(&mut pps_users, later, address(1, 2, 1));
+        assert!(!is_tracked(&mut pps_users, address(1, 2, 1)));
+        assert!(is_tracked(&mut pps_users, address(1, 1, 1)));
+
+        let after_cooldown = start + MINUTE * 6;
+        ensure_existing_activity_updated_and_migrated(
+            &mut pps_users,
+            after_cooldown,
+            address(1, 2, 1),
+        );
+        assert!(is_tracked(&mut pps_users, address(1, 2, 1)));
+        assert!(!is_tracked(&mut pps_users, address(1, 1, 1)));
+    }
+
+    #[test]
+    fn untracked_sources_share_a_rate_limit() {
+        let now = Instant::now();
+        let mut pps_users = users(options([256; 4], 1), now);
+        let rate_limit = RateLimit {
+            pixels_per_second: 1.0,
+            burst: 2.0,
+        };
+        let mut allowed = 0;
+        for fourth in 1..=4 {
+            let user_ip = address(1, fourth, 1);
+            ensure_existing_activity_updated_and_migrated(&mut pps_users, now, user_ip);
+            let (_, user_info) = find_user_info_data_or_untracked_mut(&mut pps_users, user_ip);
+            if user_info.try_take_rate_limit_token(&rate_limit, now) {
+                allowed += 1;
+            } else {
+                user_info.dropped_counter += 1;
             }
-        })
+        }
+        // The first source is tracked, the others share one bucket
+        assert_eq!(allowed, 3);
+        let counters = get_untracked_pps_counters_and_reset(&mut pps_users);
+        assert_eq!(counters.dropped, 1);
+        assert_eq!(pps_users.untracked_dropped, 1);
+        assert_eq!(
+            get_untracked_pps_counters_and_reset(&mut pps_users).dropped,
+            0
+        );
+    }
 }
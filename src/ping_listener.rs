@@ -2,13 +2,90 @@
 
 use color_eyre::Result;
 use crossbeam_channel::Sender;
+use serde::Serialize;
 use std::{
     io::{Cursor, Read},
     net::Ipv6Addr,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use crate::canvas_processor::PixelInfo;
 
+/// Counts what happened to the captured packets (shown to admins)
+pub static LISTENER_COUNTERS: ListenerCounters = ListenerCounters::new();
+
+pub struct ListenerCounters {
+    packets: AtomicU64,
+    not_ipv6: AtomicU64,
+    not_icmpv6: AtomicU64,
+    not_echo: AtomicU64,
+    bad_checksum: AtomicU64,
+    malformed: AtomicU64,
+    banned: AtomicU64,
+    not_a_pixel: AtomicU64,
+    challenges: AtomicU64,
+    pixels: AtomicU64,
+}
+
+/// Totals since the server started
+#[derive(Serialize, Clone, Copy)]
+pub struct ListenerCountersSnapshot {
+    pub packets: u64,
+    /// Not an IPv6 packet (or not ethernet type IPv6)
+    pub not_ipv6: u64,
+    pub not_icmpv6: u64,
+    /// ICMPv6, but not an echo request or reply
+    pub not_echo: u64,
+    pub bad_checksum: u64,
+    /// Truncated or too short to be a ping
+    pub malformed: u64,
+    /// Pings of banned sources (dropped)
+    pub banned: u64,
+    /// A valid ping, but the destination isn't a pixel
+    pub not_a_pixel: u64,
+    /// Pings to display name challenge addresses
+    pub challenges: u64,
+    /// Passed on to the canvas processor
+    pub pixels: u64,
+}
+
+impl ListenerCounters {
+    const fn new() -> Self {
+        Self {
+            packets: AtomicU64::new(0),
+            not_ipv6: AtomicU64::new(0),
+            not_icmpv6: AtomicU64::new(0),
+            not_echo: AtomicU64::new(0),
+            bad_checksum: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            banned: AtomicU64::new(0),
+            not_a_pixel: AtomicU64::new(0),
+            challenges: AtomicU64::new(0),
+            pixels: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ListenerCountersSnapshot {
+        ListenerCountersSnapshot {
+            packets: self.packets.load(Ordering::Relaxed),
+            not_ipv6: self.not_ipv6.load(Ordering::Relaxed),
+            not_icmpv6: self.not_icmpv6.load(Ordering::Relaxed),
+            not_echo: self.not_echo.load(Ordering::Relaxed),
+            bad_checksum: self.bad_checksum.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+            banned: self.banned.load(Ordering::Relaxed),
+            not_a_pixel: self.not_a_pixel.load(Ordering::Relaxed),
+            challenges: self.challenges.load(Ordering::Relaxed),
+            pixels: self.pixels.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// Source and destination IP of a ping
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct IpInfo {
@@ -106,6 +183,7 @@ pub fn check_for_icmpv6_ping(
                 next_header[0],
                 next_header[1]
             );*/
+            ListenerCounters::count(&LISTENER_COUNTERS.not_ipv6);
             return Ok(None);
         }
     }
@@ -119,6 +197,7 @@ pub fn check_for_icmpv6_ping(
             "Fault: IP: Not an IPv6 packet (got: {:02x}, expected: 0x60)",
             ip_header[0]
         );*/
+        ListenerCounters::count(&LISTENER_COUNTERS.not_ipv6);
         return Ok(None);
     }
     reader.read_exact(&mut ip_header[1..])?;
@@ -127,6 +206,7 @@ pub fn check_for_icmpv6_ping(
     if ip_header[6] != 0x3a {
         // "Next header" is not indicating an ICMPv6 packet. We don't care about Non-ICMP packets!
         //debug!("Fault: Next header is not ICMPv6");
+        ListenerCounters::count(&LISTENER_COUNTERS.not_icmpv6);
         return Ok(None);
     }
     // ip_header[7] is the hop limit
@@ -158,6 +238,7 @@ pub fn check_for_icmpv6_ping(
     if payload_length < 8 {
         // The ICMPv6 Packet is smaller than the smallest ping possible!
         //debug!("Fault: ICMPv6 Header too small");
+        ListenerCounters::count(&LISTENER_COUNTERS.malformed);
         return Ok(None);
     }
 
@@ -165,6 +246,7 @@ pub fn check_for_icmpv6_ping(
     reader.read_exact(&mut icmp_packet)?;
     if (icmp_packet[0] != 0x80 && icmp_packet[0] != 0x81) || icmp_packet[1] != 0x00 {
         // not ping request or reply or not Code (0x00)!
+        ListenerCounters::count(&LISTENER_COUNTERS.not_echo);
         return Ok(None);
     }
 
@@ -184,6 +266,7 @@ pub fn check_for_icmpv6_ping(
                 expected_icmp_checksum,
                 icmp_checksum
             );*/
+            ListenerCounters::count(&LISTENER_COUNTERS.bad_checksum);
             return Ok(None);
         }
     }
@@ -213,16 +296,31 @@ pub fn run_ping_listener(
     );
 
     iface.loop_infinite_dyn(&|packet| {
+        ListenerCounters::count(&LISTENER_COUNTERS.packets);
         let res = check_for_icmpv6_ping(&packet, is_ethernet, require_valid_icmpv6_checksum);
         match res {
             Ok(Some(ip_info)) => {
                 //info!("Got ping from {} to {}", ip_info.src_ip, ip_info.dest_ip);
+                // Before anything else, so banned sources can't draw or claim names
+                if crate::bans::record_if_banned(ip_info.src_ip) {
+                    ListenerCounters::count(&LISTENER_COUNTERS.banned);
+                    return;
+                }
+                #[cfg(feature = "per_user_pps")]
+                if crate::display_names::handle_challenge_ping(&ip_info) {
+                    ListenerCounters::count(&LISTENER_COUNTERS.challenges);
+                    return;
+                }
                 let pixel_info: Option<PixelInfo> = PixelInfo::from_ip_info(ip_info);
                 if let Some(pixel_info) = pixel_info {
+                    ListenerCounters::count(&LISTENER_COUNTERS.pixels);
                     pixel_sender.send(pixel_info).ok();
+                } else {
+                    ListenerCounters::count(&LISTENER_COUNTERS.not_a_pixel);
                 }
             }
-            _ => {}
+            Ok(None) => {}
+            Err(_) => ListenerCounters::count(&LISTENER_COUNTERS.malformed),
         }
     })?;
     Err(color_eyre::eyre::eyre!(
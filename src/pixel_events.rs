@@ -0,0 +1,239 @@
+//! Pixel writes as they come out of the canvas processor (for bots and analytics).
+//!
+//! Events are batched per canvas update and can be filtered by region and user id.
+//! They are available on the websocket and as stream on /pixel_events,
+//! either as JSON or in a compact binary encoding (see `to_binary`).
+
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    body::StreamBody,
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::Rgb;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::canvas::CanvasState;
+use crate::censor::CensoredEndpoint;
+use crate::palette::serialize_hex_color;
+
+/// Events beyond this per canvas update are only counted
+const MAX_EVENTS_PER_BATCH: usize = 65_536;
+/// Size of the header and of each event in the binary encoding
+const BINARY_HEADER_LEN: usize = 16;
+const BINARY_EVENT_LEN: usize = 16;
+
+#[derive(Serialize, Clone, Copy)]
+pub struct PixelEvent {
+    pub x: u16,
+    pub y: u16,
+    pub size: u8,
+    #[serde(serialize_with = "serialize_hex_color")]
+    pub color: Rgb<u8>,
+    /// Public user id (if known)
+    pub user_id: Option<u64>,
+}
+
+/// All pixels drawn during one canvas update
+#[derive(Serialize, Clone, Default)]
+pub struct PixelEventBatch {
+    /// Unix time in milliseconds of the canvas update
+    pub at_ms: u64,
+    pub events: Vec<PixelEvent>,
+    /// Pixels which were drawn but not included (see MAX_EVENTS_PER_BATCH)
+    pub skipped: usize,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PixelEventEncoding {
+    #[default]
+    Json,
+    Binary,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct Region {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Clone, Default)]
+pub struct PixelEventFilter {
+    /// Only events which cover at least one pixel of the region
+    pub region: Option<Region>,
+    /// Only events of these users
+    pub user_ids: Option<Vec<u64>>,
+}
+
+impl PixelEventFilter {
+    fn matches(&self, event: &PixelEvent) -> bool {
+        if let Some(region) = &self.region {
+            let size = event.size as u32;
+            if event.x as u32 + size <= region.x as u32
+                || event.y as u32 + size <= region.y as u32
+                || event.x as u32 >= region.x as u32 + region.width as u32
+                || event.y as u32 >= region.y as u32 + region.height as u32
+            {
+                return false;
+            }
+        }
+        if let Some(user_ids) = &self.user_ids {
+            if !event
+                .user_id
+                .map(|user_id| user_ids.contains(&user_id))
+                .unwrap_or(false)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl PixelEventBatch {
+    /// Add the event (or only count it if the batch is full)
+    #[inline]
+    pub fn push(&mut self, event: PixelEvent) {
+        if self.events.len() < MAX_EVENTS_PER_BATCH {
+            self.events.push(event);
+        } else {
+            self.skipped += 1;
+        }
+    }
+
+    /// Only the events matching the filter. None if there are none.
+    pub fn filter(&self, filter: &PixelEventFilter) -> Option<PixelEventBatch> {
+        let events: Vec<_> = self
+            .events
+            .iter()
+            .filter(|event| filter.matches(event))
+            .copied()
+            .collect();
+        if events.is_empty() && self.skipped == 0 {
+            return None;
+        }
+        Some(PixelEventBatch {
+            at_ms: self.at_ms,
+            events,
+            skipped: self.skipped,
+        })
+    }
+
+    /// Big endian. Header: at_ms (u64), event count (u32), skipped (u32).
+    /// Each event: x (u16), y (u16), size (u8), r, g, b (u8), user id (u64, 0 if unknown).
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(BINARY_HEADER_LEN + self.events.len() * BINARY_EVENT_LEN);
+        data.extend_from_slice(&self.at_ms.to_be_bytes());
+        data.extend_from_slice(&(self.events.len() as u32).to_be_bytes());
+        data.extend_from_slice(&(self.skipped as u32).to_be_bytes());
+        for event in &self.events {
+            data.extend_from_slice(&event.x.to_be_bytes());
+            data.extend_from_slice(&event.y.to_be_bytes());
+            data.push(event.size);
+            data.extend_from_slice(&event.color.0);
+            data.extend_from_slice(&event.user_id.unwrap_or(0).to_be_bytes());
+        }
+        data
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PixelEventsQuery {
+    encoding: Option<PixelEventEncoding>,
+    x: Option<u16>,
+    y: Option<u16>,
+    width: Option<u16>,
+    height: Option<u16>,
+    /// Comma separated
+    user_ids: Option<String>,
+    admin_token: Option<String>,
+}
+
+impl PixelEventsQuery {
+    fn filter(&self) -> Result<PixelEventFilter, String> {
+        let region = match (self.x, self.y, self.width, self.height) {
+            (Some(x), Some(y), Some(width), Some(height)) => Some(Region {
+                x,
+                y,
+                width,
+                height,
+            }),
+            (None, None, None, None) => None,
+            _ => return Err(String::from("Region needs x, y, width and height")),
+        };
+        let user_ids = match &self.user_ids {
+            Some(user_ids) => Some(
+                user_ids
+                    .split(',')
+                    .map(|user_id| user_id.trim().parse::<u64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|err| format!("Invalid user id: {err}"))?,
+            ),
+            None => None,
+        };
+        Ok(PixelEventFilter { region, user_ids })
+    }
+}
+
+/// Stream of pixel event batches: One JSON object per line (NDJSON) or the
+/// binary encoding of each batch. Ends if the client can't keep up.
+/// Like /canvas.png, batches are left out while the canvas is censored (unless admin).
+pub async fn get_pixel_events(
+    State(canvas_state): State<Arc<CanvasState>>,
+    Query(query): Query<PixelEventsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let filter = query
+        .filter()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let encoding = query.encoding.unwrap_or_default();
+    let censor = crate::is_censored_endpoint(CensoredEndpoint::CanvasPng)
+        && !crate::admin::is_admin(&headers, query.admin_token.as_deref());
+    let receiver = canvas_state.subscribe_to_pixel_events();
+    let stream = futures_util::stream::unfold((receiver, filter), move |(mut receiver, filter)| {
+        let canvas_state = canvas_state.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(batch) => {
+                        if censor && canvas_state.is_censored().await {
+                            continue;
+                        }
+                        let Some(batch) = batch.filter(&filter) else {
+                            continue;
+                        };
+                        let data = match encoding {
+                            PixelEventEncoding::Json => {
+                                let mut line = serde_json::to_vec(&batch).ok()?;
+                                line.push(b'\n');
+                                line
+                            }
+                            PixelEventEncoding::Binary => batch.to_binary(),
+                        };
+                        return Some((Ok::<_, Infallible>(data), (receiver, filter)));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Pixel events: Ending stream which lagged behind {skipped} batches");
+                        return None;
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    let content_type = match encoding {
+        PixelEventEncoding::Json => "application/x-ndjson",
+        PixelEventEncoding::Binary => "application/octet-stream",
+    };
+    Ok((
+        [(header::CONTENT_TYPE, content_type)],
+        StreamBody::new(stream),
+    )
+        .into_response())
+}
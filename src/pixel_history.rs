@@ -0,0 +1,118 @@
+//! Bounded log of all pixel writes. Used to roll back what a user or prefix drew.
+
+use std::{collections::HashMap, collections::VecDeque, net::Ipv6Addr};
+
+use image::Rgb;
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+
+use crate::pixel_provenance::PixelWriter;
+
+/// Whose pixels to roll back
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RollbackTarget {
+    /// Public user id (see per_user_pps.rs)
+    UserId(u64),
+    Prefix(Ipv6Net),
+}
+
+pub struct PixelWrite {
+    pub x: u16,
+    pub y: u16,
+    /// None for writes not caused by pings (e.g. rollbacks)
+    pub source: Option<Ipv6Addr>,
+    pub writer: PixelWriter,
+    pub previous_color: Rgb<u8>,
+    pub previous_writer: PixelWriter,
+    /// Whether the pixel was painted before (or showed the background layer)
+    pub previous_painted: bool,
+}
+
+impl PixelWrite {
+    fn is_by(&self, target: RollbackTarget) -> bool {
+        match target {
+            RollbackTarget::UserId(user_id) => self.writer.user_id == Some(user_id),
+            RollbackTarget::Prefix(prefix) => self
+                .source
+                .map(|source| prefix.contains(&source))
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// How a pixel should look after a rollback
+pub struct RestoredPixel {
+    pub x: u16,
+    pub y: u16,
+    pub color: Rgb<u8>,
+    pub writer: PixelWriter,
+    pub painted: bool,
+}
+
+pub struct PixelHistory {
+    writes: VecDeque<PixelWrite>,
+    max_len: usize,
+}
+
+impl PixelHistory {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            // Grows with the writes instead of reserving max_len up front
+            writes: VecDeque::new(),
+            max_len,
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, write: PixelWrite) {
+        if self.max_len == 0 {
+            return;
+        }
+        if self.writes.len() >= self.max_len {
+            self.writes.pop_front();
+        }
+        self.writes.push_back(write);
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+
+    /// Forget the writes outside of the given area (e.g. after the canvas shrunk)
+    pub fn retain_inside(&mut self, width: u16, height: u16) {
+        self.writes
+            .retain(|write| write.x < width && write.y < height);
+    }
+
+    /// Unix time in milliseconds of the oldest remembered write
+    pub fn oldest_write_ms(&self) -> Option<u64> {
+        self.writes.front().map(|write| write.writer.written_at_ms)
+    }
+
+    /// Find out how to revert all pixels which target wrote in the given time range.
+    ///
+    /// A pixel is restored to what it looked like before the target started writing it.
+    /// Pixels which got written by anyone else afterwards are kept as they are.
+    pub fn rollback(&self, target: RollbackTarget, from_ms: u64, to_ms: u64) -> Vec<RestoredPixel> {
+        let mut pending: HashMap<(u16, u16), RestoredPixel> = HashMap::new();
+        for write in &self.writes {
+            let written_at_ms = write.writer.written_at_ms;
+            if write.is_by(target) && written_at_ms >= from_ms && written_at_ms <= to_ms {
+                pending
+                    .entry((write.x, write.y))
+                    .or_insert_with(|| RestoredPixel {
+                        x: write.x,
+                        y: write.y,
+                        color: write.previous_color,
+                        writer: write.previous_writer,
+                        painted: write.previous_painted,
+                    });
+            } else {
+                // Someone else painted over it later on (or it was rolled back already)
+                pending.remove(&(write.x, write.y));
+            }
+        }
+        pending.into_values().collect()
+    }
+}
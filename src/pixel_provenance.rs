@@ -0,0 +1,120 @@
+//! Remembers which user last wrote each pixel and when.
+//! Only public user ids (see per_user_pps.rs) are stored, never addresses.
+//!
+//! Uses atomics so lookups from the webserver never block the canvas processor.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::canvas::{CANVASH, CANVASW};
+
+/// Used if a pixel was written by an unknown user (per user tracking not possible)
+const UNKNOWN_USER_ID: u64 = 0;
+/// Used if a pixel was never written
+const NEVER_WRITTEN: u64 = 0;
+
+pub struct PixelProvenance {
+    user_ids: Box<[AtomicU64]>,
+    /// Unix time in milliseconds
+    written_at: Box<[AtomicU64]>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct PixelOwner {
+    pub x: u16,
+    pub y: u16,
+    /// Public id of the user which last wrote the pixel (if known)
+    pub user_id: Option<u64>,
+    /// Unix time in milliseconds of the last write (None if never written)
+    pub written_at_ms: Option<u64>,
+    pub written_ago_ms: Option<u64>,
+}
+
+/// Who wrote a pixel when
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PixelWriter {
+    pub user_id: Option<u64>,
+    /// Unix time in milliseconds (0 if never written)
+    pub written_at_ms: u64,
+}
+
+impl PixelWriter {
+    pub const NONE: PixelWriter = PixelWriter {
+        user_id: None,
+        written_at_ms: NEVER_WRITTEN,
+    };
+
+    fn from_raw(user_id: u64, written_at_ms: u64) -> Self {
+        Self {
+            user_id: (user_id != UNKNOWN_USER_ID).then_some(user_id),
+            written_at_ms,
+        }
+    }
+}
+
+impl Default for PixelProvenance {
+    fn default() -> Self {
+        let len = CANVASW as usize * CANVASH as usize;
+        Self {
+            user_ids: (0..len).map(|_| AtomicU64::new(UNKNOWN_USER_ID)).collect(),
+            written_at: (0..len).map(|_| AtomicU64::new(NEVER_WRITTEN)).collect(),
+        }
+    }
+}
+
+impl PixelProvenance {
+    #[inline]
+    fn index(x: u16, y: u16) -> Option<usize> {
+        if x >= CANVASW || y >= CANVASH {
+            return None;
+        }
+        Some(y as usize * CANVASW as usize + x as usize)
+    }
+
+    /// Remember the writer of a pixel. Returns the previous writer.
+    #[inline]
+    pub fn record(&self, x: u16, y: u16, writer: PixelWriter) -> PixelWriter {
+        match Self::index(x, y) {
+            Some(index) => PixelWriter::from_raw(
+                self.user_ids[index]
+                    .swap(writer.user_id.unwrap_or(UNKNOWN_USER_ID), Ordering::Relaxed),
+                self.written_at[index].swap(writer.written_at_ms, Ordering::Relaxed),
+            ),
+            None => PixelWriter::NONE,
+        }
+    }
+
+    /// Get the last writer of a pixel. None if outside the canvas.
+    pub fn get(&self, x: u16, y: u16) -> Option<PixelOwner> {
+        let index = Self::index(x, y)?;
+        let user_id = self.user_ids[index].load(Ordering::Relaxed);
+        let written_at_ms = self.written_at[index].load(Ordering::Relaxed);
+        let written_at_ms = (written_at_ms != NEVER_WRITTEN).then_some(written_at_ms);
+        Some(PixelOwner {
+            x,
+            y,
+            user_id: (user_id != UNKNOWN_USER_ID).then_some(user_id),
+            written_at_ms,
+            written_ago_ms: written_at_ms
+                .map(|written_at_ms| unix_millis(SystemTime::now()).saturating_sub(written_at_ms)),
+        })
+    }
+
+    /// Forget all writers (e.g. after the canvas got reset)
+    pub fn clear(&self) {
+        for (user_id, written_at) in self.user_ids.iter().zip(self.written_at.iter()) {
+            user_id.store(UNKNOWN_USER_ID, Ordering::Relaxed);
+            written_at.store(NEVER_WRITTEN, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,112 @@
+//! Remembers recent PPS samples so graphs don't start empty:
+//! Every second for the last 10 minutes and averages per minute for the last 24 hours.
+
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use crate::canvas::PpsInfo;
+
+const SECOND_SAMPLES: usize = 10 * 60;
+const MINUTE_SAMPLES: usize = 24 * 60;
+/// Only the users with the most PPS are kept per sample (the total stays exact)
+#[cfg(feature = "per_user_pps")]
+const MAX_USERS_PER_SAMPLE: usize = 64;
+
+#[derive(Serialize, Clone)]
+pub struct PpsSample {
+    /// Unix time in milliseconds
+    pub at_ms: u64,
+    pub pps: usize,
+    #[cfg(feature = "per_user_pps")]
+    pub per_user_pps: fxhash::FxHashMap<u64, usize>,
+}
+
+#[cfg(feature = "per_user_pps")]
+fn top_users(per_user_pps: &fxhash::FxHashMap<u64, usize>) -> fxhash::FxHashMap<u64, usize> {
+    if per_user_pps.len() <= MAX_USERS_PER_SAMPLE {
+        return per_user_pps.clone();
+    }
+    let mut users: Vec<_> = per_user_pps.iter().map(|(id, pps)| (*id, *pps)).collect();
+    users.sort_unstable_by_key(|(id, pps)| (std::cmp::Reverse(*pps), *id));
+    users.into_iter().take(MAX_USERS_PER_SAMPLE).collect()
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct PpsHistory {
+    /// Oldest first
+    pub seconds: VecDeque<PpsSample>,
+    /// Oldest first. Averages of each minute (at_ms is the start of the minute).
+    pub minutes: VecDeque<PpsSample>,
+    /// Sums of the samples in the current minute
+    #[serde(skip)]
+    current_minute: Option<MinuteSums>,
+}
+
+#[derive(Clone)]
+struct MinuteSums {
+    minute: u64,
+    samples: usize,
+    pps: usize,
+    #[cfg(feature = "per_user_pps")]
+    per_user_pps: fxhash::FxHashMap<u64, usize>,
+}
+
+impl MinuteSums {
+    fn average(&self) -> PpsSample {
+        PpsSample {
+            at_ms: self.minute * 60 * 1000,
+            pps: self.pps / self.samples,
+            #[cfg(feature = "per_user_pps")]
+            per_user_pps: top_users(
+                &self
+                    .per_user_pps
+                    .iter()
+                    .map(|(id, pps)| (*id, pps / self.samples))
+                    .filter(|(_, pps)| *pps > 0)
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl PpsHistory {
+    /// Add a sample (expected about once per second)
+    pub fn record(&mut self, pps_info: &PpsInfo, now_ms: u64) {
+        let sample = PpsSample {
+            at_ms: now_ms,
+            pps: pps_info.pps,
+            #[cfg(feature = "per_user_pps")]
+            per_user_pps: top_users(&pps_info.per_user_pps),
+        };
+
+        let minute = now_ms / (60 * 1000);
+        if let Some(sums) = &self.current_minute {
+            if sums.minute != minute {
+                self.minutes.push_back(sums.average());
+                if self.minutes.len() > MINUTE_SAMPLES {
+                    self.minutes.pop_front();
+                }
+                self.current_minute = None;
+            }
+        }
+        let sums = self.current_minute.get_or_insert_with(|| MinuteSums {
+            minute,
+            samples: 0,
+            pps: 0,
+            #[cfg(feature = "per_user_pps")]
+            per_user_pps: Default::default(),
+        });
+        sums.samples += 1;
+        sums.pps += pps_info.pps;
+        #[cfg(feature = "per_user_pps")]
+        for (user_id, pps) in &pps_info.per_user_pps {
+            *sums.per_user_pps.entry(*user_id).or_insert(0) += pps;
+        }
+
+        self.seconds.push_back(sample);
+        if self.seconds.len() > SECOND_SAMPLES {
+            self.seconds.pop_front();
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Rectangles on the canvas which pings are not allowed to draw on
+//! (e.g. to keep a banner or logo intact during events).
+//! Optionally a mask limits the protection to some pixels of the rectangle
+//! and allowed prefixes can still draw inside the region.
+//!
+//! The canvas processor reads the published regions, which get replaced
+//! whenever they change (so it never has to lock the server config).
+
+use std::{
+    net::Ipv6Addr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use arc_swap::ArcSwapOption;
+use color_eyre::{
+    eyre::{ensure, Context},
+    Result,
+};
+use image::RgbaImage;
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::canvas::{CANVASH, CANVASW};
+use crate::SERVER_CONFIG;
+
+/// The current regions as used by the canvas processor (see publish)
+static PUBLISHED_REGIONS: ArcSwapOption<Vec<ProtectedRegion>> = ArcSwapOption::const_empty();
+/// Held while saving, so an older state can't overwrite a newer one
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProtectedRegion {
+    pub name: String,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    /// Image of which all non-transparent pixels are protected (relative to x and y).
+    /// The whole rectangle is protected if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask: Option<PathBuf>,
+    /// Pings from these prefixes are still allowed to draw inside this region
+    #[serde(default)]
+    pub allowed_prefixes: Vec<Ipv6Net>,
+    #[serde(skip)]
+    mask_image: Option<Arc<RgbaImage>>,
+}
+
+/// What frontends get to see about a protected region
+#[derive(Serialize)]
+struct PublicProtectedRegion<'a> {
+    name: &'a str,
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+    has_mask: bool,
+}
+
+impl ProtectedRegion {
+    /// Validate the region and load its mask (if any)
+    pub fn prepare(&mut self) -> Result<()> {
+        ensure!(!self.name.is_empty(), "Protected region has no name");
+        ensure!(
+            self.width > 0
+                && self.height > 0
+                && self.x as u32 + self.width as u32 <= CANVASW as u32
+                && self.y as u32 + self.height as u32 <= CANVASH as u32,
+            "Protected region {:?} is empty or not inside the canvas",
+            self.name
+        );
+        self.mask_image = match &self.mask {
+            Some(path) => Some(Arc::new(
+                image::open(path)
+                    .with_context(|| format!("Loading mask {path:?} of region {:?}", self.name))?
+                    .to_rgba8(),
+            )),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Whether a pixel from source is not allowed to be drawn at the given position
+    #[inline]
+    pub fn protects(&self, x: u16, y: u16, source: Ipv6Addr) -> bool {
+        if x < self.x || y < self.y || x >= self.x + self.width || y >= self.y + self.height {
+            return false;
+        }
+        if let Some(mask_image) = &self.mask_image {
+            let (mask_x, mask_y) = ((x - self.x) as u32, (y - self.y) as u32);
+            if mask_x >= mask_image.width()
+                || mask_y >= mask_image.height()
+                || mask_image.get_pixel(mask_x, mask_y).0[3] == 0
+            {
+                return false;
+            }
+        }
+        !self
+            .allowed_prefixes
+            .iter()
+            .any(|prefix| prefix.contains(&source))
+    }
+}
+
+/// Validate all regions, load their masks and ensure that names are unique
+pub fn prepare_all(regions: &mut [ProtectedRegion]) -> Result<()> {
+    for (i, region) in regions.iter().enumerate() {
+        ensure!(
+            !regions[..i].iter().any(|other| other.name == region.name),
+            "Protected region {:?} exists more than once",
+            region.name
+        );
+    }
+    for region in regions.iter_mut() {
+        region.prepare()?;
+    }
+    Ok(())
+}
+
+/// Replace the regions used by the canvas processor. Call whenever the regions changed.
+pub fn publish(regions: &[ProtectedRegion]) {
+    PUBLISHED_REGIONS.store(Some(Arc::new(regions.to_vec())));
+}
+
+/// The currently published regions
+pub fn published() -> Arc<Vec<ProtectedRegion>> {
+    PUBLISHED_REGIONS.load_full().unwrap_or_default()
+}
+
+pub fn load_from_file(path: &Path) -> Result<Vec<ProtectedRegion>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Opening {path:?}"))?;
+    let mut regions: Vec<ProtectedRegion> =
+        serde_json::from_reader(file).with_context(|| format!("Parsing {path:?}"))?;
+    prepare_all(&mut regions)?;
+    Ok(regions)
+}
+
+fn save_to_file(path: &Path, regions: &[ProtectedRegion]) -> Result<()> {
+    let json = serde_json::to_string_pretty(regions)?;
+    std::fs::write(path, json).with_context(|| format!("Writing {path:?}"))
+}
+
+/// Persist the current regions if they were loaded from a file. Blocks, but
+/// SERVER_CONFIG is only locked while copying the regions (not while writing).
+pub fn save() -> Result<()> {
+    let _saving = SAVE_LOCK.lock().unwrap();
+    let (regions, file) = {
+        let server_config = SERVER_CONFIG.lock().unwrap();
+        (
+            server_config.protected_regions.clone(),
+            server_config.protected_regions_file.clone(),
+        )
+    };
+    match file {
+        Some(file) => save_to_file(&file, &regions),
+        None => Ok(()),
+    }
+}
+
+/// Serialize without the allowed prefixes and mask paths (used for /serverconfig.json)
+pub fn serialize_public<S: Serializer>(
+    regions: &[ProtectedRegion],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(regions.iter().map(|region| PublicProtectedRegion {
+        name: &region.name,
+        x: region.x,
+        y: region.y,
+        width: region.width,
+        height: region.height,
+        has_mask: region.mask_image.is_some(),
+    }))
+}
@@ -0,0 +1,216 @@
+//! Events planned ahead of time in a schedule file: Resetting the canvas
+//! (after archiving it), freezing it for a while and resizing it.
+//!
+//! The canvas can only be resized up to CANVASW x CANVASH. Only the active
+//! area (top left) can be drawn on and is sent to clients. Shrinking clears the
+//! pixels outside of the new area, so they don't show up again when it grows.
+
+use std::path::Path;
+
+use color_eyre::{
+    eyre::{ensure, Context},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::canvas::{CANVASH, CANVASW};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ScheduledEvent {
+    /// Unix time in seconds
+    pub at: u64,
+    /// How many seconds ahead of time clients get told about the event
+    #[serde(default = "default_announce_secs")]
+    pub announce_secs: u64,
+    #[serde(flatten)]
+    pub action: ScheduledAction,
+}
+
+fn default_announce_secs() -> u64 {
+    5 * 60
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ScheduledAction {
+    /// Archive and clear the canvas
+    Reset,
+    /// Drop all pixels for a while
+    Freeze { duration_secs: u64 },
+    /// Change the size of the active area
+    Resize { width: u16, height: u16 },
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventPhase {
+    Announced,
+    Started,
+    /// Only for freezes
+    Ended,
+}
+
+/// Sent to clients when something about a scheduled event happens
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct CanvasEvent {
+    pub phase: EventPhase,
+    #[serde(flatten)]
+    pub event: ScheduledEvent,
+}
+
+impl ScheduledEvent {
+    fn at_ms(&self) -> u64 {
+        self.at.saturating_mul(1000)
+    }
+
+    fn announce_at_ms(&self) -> u64 {
+        self.at_ms()
+            .saturating_sub(self.announce_secs.saturating_mul(1000))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let ScheduledAction::Resize { width, height } = self.action {
+            ensure!(
+                width > 0 && height > 0 && width <= CANVASW && height <= CANVASH,
+                "Can't resize to {width}x{height} (at most {CANVASW}x{CANVASH})"
+            );
+        }
+        Ok(())
+    }
+}
+
+pub fn load_from_file(path: &Path) -> Result<Vec<ScheduledEvent>> {
+    let events: Vec<ScheduledEvent> = serde_json::from_str(
+        &std::fs::read_to_string(path).with_context(|| format!("Reading {path:?}"))?,
+    )
+    .with_context(|| format!("Parsing {path:?}"))?;
+    for event in &events {
+        event
+            .validate()
+            .with_context(|| format!("Invalid event {event:?} in {path:?}"))?;
+    }
+    Ok(events)
+}
+
+pub struct Scheduler {
+    /// Not yet started events (sorted by time)
+    pending: Vec<ScheduledEvent>,
+    announced: Vec<bool>,
+    /// Freeze that ends last
+    active_freeze: Option<(ScheduledEvent, u64)>,
+    width: u16,
+    height: u16,
+}
+
+impl Scheduler {
+    /// Events which already happened are not repeated. Only their outcome
+    /// (last size and an ongoing freeze) is applied.
+    pub fn new(mut events: Vec<ScheduledEvent>, now_ms: u64) -> Self {
+        events.sort_by_key(|event| event.at);
+        let mut scheduler = Self {
+            pending: Vec::new(),
+            announced: Vec::new(),
+            active_freeze: None,
+            width: CANVASW,
+            height: CANVASH,
+        };
+        for event in events {
+            if event.at_ms() > now_ms {
+                scheduler.pending.push(event);
+                scheduler.announced.push(false);
+            } else {
+                scheduler.apply_state(event);
+            }
+        }
+        if let Some((_, frozen_until_ms)) = scheduler.active_freeze {
+            if frozen_until_ms <= now_ms {
+                scheduler.active_freeze = None;
+            }
+        }
+        scheduler
+    }
+
+    /// Remember how the event changes the size or freeze
+    fn apply_state(&mut self, event: ScheduledEvent) {
+        match event.action {
+            ScheduledAction::Reset => {}
+            ScheduledAction::Freeze { duration_secs } => {
+                let frozen_until_ms = event.at_ms() + duration_secs.saturating_mul(1000);
+                if self
+                    .active_freeze
+                    .map(|(_, until)| frozen_until_ms > until)
+                    .unwrap_or(true)
+                {
+                    self.active_freeze = Some((event, frozen_until_ms));
+                }
+            }
+            ScheduledAction::Resize { width, height } => {
+                self.width = width;
+                self.height = height;
+            }
+        }
+    }
+
+    /// Find out what happened since the last poll. Started events need
+    /// to be executed by the caller.
+    pub fn poll(&mut self, now_ms: u64) -> Vec<CanvasEvent> {
+        let mut canvas_events = Vec::new();
+        if let Some((event, frozen_until_ms)) = self.active_freeze {
+            if now_ms >= frozen_until_ms {
+                self.active_freeze = None;
+                canvas_events.push(CanvasEvent {
+                    phase: EventPhase::Ended,
+                    event,
+                });
+            }
+        }
+        for (event, announced) in self.pending.iter().zip(self.announced.iter_mut()) {
+            if !*announced && now_ms >= event.announce_at_ms() {
+                *announced = true;
+                canvas_events.push(CanvasEvent {
+                    phase: EventPhase::Announced,
+                    event: *event,
+                });
+            }
+        }
+        while self
+            .pending
+            .first()
+            .map(|event| now_ms >= event.at_ms())
+            .unwrap_or(false)
+        {
+            let event = self.pending.remove(0);
+            self.announced.remove(0);
+            self.apply_state(event);
+            canvas_events.push(CanvasEvent {
+                phase: EventPhase::Started,
+                event,
+            });
+        }
+        canvas_events
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.active_freeze.is_some()
+    }
+
+    /// Unix time in milliseconds
+    pub fn frozen_until_ms(&self) -> Option<u64> {
+        self.active_freeze.map(|(_, until)| until)
+    }
+
+    /// Size of the active area
+    pub fn size(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    /// Upcoming events which clients already know about
+    pub fn announced_events(&self) -> Vec<ScheduledEvent> {
+        self.pending
+            .iter()
+            .zip(&self.announced)
+            .filter(|(_, announced)| **announced)
+            .map(|(event, _)| *event)
+            .collect()
+    }
+}
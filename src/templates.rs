@@ -0,0 +1,485 @@
+//! Templates are images (with an offset) which communities want to see on the canvas.
+//! Transparent pixels of a template mean "don't care".
+//!
+//! The template checker thread regularly compares all templates with a snapshot of
+//! the canvas to find out how complete they are and which pixels still need fixing.
+//! It runs separately, so templates can't slow down the canvas processor.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path as UrlPath, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{AppendHeaders, IntoResponse},
+    routing::get,
+    Json, Router,
+};
+use color_eyre::{
+    eyre::{ensure, Context},
+    Result,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use image::{DynamicImage, ImageFormat, Rgb, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::admin::RequireAdmin;
+use crate::canvas::{CanvasState, CANVASH, CANVASW};
+use crate::palette::serialize_hex_color;
+use crate::SERVER_CONFIG;
+
+/// Lists name and offset of all templates in the templates directory
+const INDEX_FILE_NAME: &str = "templates.json";
+/// Only one save at a time
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+pub struct Template {
+    pub name: String,
+    pub x: u16,
+    pub y: u16,
+    image: RgbaImage,
+    /// Original PNG
+    encoded: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TemplateIndexEntry {
+    name: String,
+    x: u16,
+    y: u16,
+}
+
+#[derive(Serialize, Clone)]
+pub struct TemplateProgress {
+    pub name: String,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    /// Non-transparent pixels of the template
+    pub total_pixels: u32,
+    pub matching_pixels: u32,
+    pub completion_percent: f64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct TemplateStatus {
+    #[serde(flatten)]
+    pub progress: TemplateProgress,
+    pub mismatches: Vec<TemplateMismatch>,
+}
+
+/// Pixel on the canvas which doesn't look like the template yet
+#[derive(Serialize, Clone, Copy)]
+pub struct TemplateMismatch {
+    pub x: u16,
+    pub y: u16,
+    #[serde(serialize_with = "serialize_hex_color")]
+    pub expected: Rgb<u8>,
+}
+
+impl Template {
+    /// Decode and validate a template (PNG)
+    pub fn new(name: String, x: u16, y: u16, encoded: Vec<u8>) -> Result<Self> {
+        ensure!(
+            !name.is_empty()
+                && name.len() <= 64
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "Template names need to be 1 to 64 characters long and may only contain a-z, A-Z, 0-9, - and _"
+        );
+        let image = image::load_from_memory_with_format(&encoded, ImageFormat::Png)
+            .with_context(|| format!("Decoding template {name:?} as PNG"))?
+            .to_rgba8();
+        ensure!(
+            image.width() > 0
+                && image.height() > 0
+                && x as u32 + image.width() <= CANVASW as u32
+                && y as u32 + image.height() <= CANVASH as u32,
+            "Template {name:?} is empty or not inside the canvas"
+        );
+        Ok(Self {
+            name,
+            x,
+            y,
+            image,
+            encoded,
+        })
+    }
+
+    pub fn encoded(&self) -> &[u8] {
+        &self.encoded
+    }
+
+    /// Pixels which get compared with the canvas (including transparent ones)
+    pub fn area(&self) -> u64 {
+        self.image.width() as u64 * self.image.height() as u64
+    }
+
+    /// Compare all non-transparent pixels with the canvas
+    pub fn check(&self, canvas: &DynamicImage) -> TemplateStatus {
+        let canvas = canvas.as_rgb8().unwrap();
+        let mut total_pixels = 0;
+        let mut mismatches = Vec::new();
+        for (template_x, template_y, pixel) in self.image.enumerate_pixels() {
+            if pixel.0[3] == 0 {
+                continue;
+            }
+            total_pixels += 1;
+            let (x, y) = (self.x + template_x as u16, self.y + template_y as u16);
+            let expected = Rgb([pixel.0[0], pixel.0[1], pixel.0[2]]);
+            if *canvas.get_pixel(x as u32, y as u32) != expected {
+                mismatches.push(TemplateMismatch { x, y, expected });
+            }
+        }
+        let matching_pixels = total_pixels - mismatches.len() as u32;
+        TemplateStatus {
+            progress: TemplateProgress {
+                name: self.name.clone(),
+                x: self.x,
+                y: self.y,
+                width: self.image.width() as u16,
+                height: self.image.height() as u16,
+                total_pixels,
+                matching_pixels,
+                completion_percent: if total_pixels > 0 {
+                    matching_pixels as f64 * 100.0 / total_pixels as f64
+                } else {
+                    100.0
+                },
+            },
+            mismatches,
+        }
+    }
+}
+
+/// Used by the template checker to only check the templates
+/// when needed and not more often than the configured interval.
+pub struct TemplateChecker {
+    interval: Duration,
+    last_checked_at: Option<Instant>,
+    canvas_changed: bool,
+    checked_templates: Vec<Arc<Template>>,
+}
+
+impl TemplateChecker {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_checked_at: None,
+            canvas_changed: true,
+            checked_templates: Vec::new(),
+        }
+    }
+
+    pub fn canvas_changed(&mut self) {
+        self.canvas_changed = true;
+    }
+
+    /// Check all templates if the canvas or templates changed and the interval has passed
+    pub fn check_if_due(
+        &mut self,
+        now: Instant,
+        canvas: &DynamicImage,
+        templates: Vec<Arc<Template>>,
+    ) -> Option<Vec<TemplateStatus>> {
+        if let Some(last_checked_at) = self.last_checked_at {
+            if now.saturating_duration_since(last_checked_at) < self.interval {
+                return None;
+            }
+        }
+        let templates_changed = templates.len() != self.checked_templates.len()
+            || templates
+                .iter()
+                .zip(&self.checked_templates)
+                .any(|(a, b)| !Arc::ptr_eq(a, b));
+        if !self.canvas_changed && !templates_changed {
+            return None;
+        }
+        self.last_checked_at = Some(now);
+        self.canvas_changed = false;
+        let statuses = templates
+            .iter()
+            .map(|template| template.check(canvas))
+            .collect();
+        self.checked_templates = templates;
+        Some(statuses)
+    }
+}
+
+/// Check the templates against the latest canvas snapshot sent by the canvas processor
+pub fn run_template_checker(
+    canvas_receiver: Receiver<DynamicImage>,
+    canvas_state: Arc<CanvasState>,
+    interval: Duration,
+) -> Result<()> {
+    let mut checker = TemplateChecker::new(interval);
+    let mut canvas = None;
+    loop {
+        match canvas_receiver.recv_timeout(interval) {
+            Ok(snapshot) => {
+                canvas = Some(snapshot);
+                checker.canvas_changed();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        let Some(canvas) = &canvas else {
+            continue;
+        };
+        if let Some(statuses) =
+            checker.check_if_due(Instant::now(), canvas, canvas_state.blocking_templates())
+        {
+            canvas_state.blocking_update_template_statuses(statuses);
+        }
+    }
+    Err(color_eyre::eyre::eyre!(
+        "The template checker failed to get a canvas!"
+    ))
+}
+
+pub fn load_from_dir(dir: &Path) -> Result<Vec<Arc<Template>>> {
+    let index_path = dir.join(INDEX_FILE_NAME);
+    if !index_path.exists() {
+        return Ok(vec![]);
+    }
+    let index: Vec<TemplateIndexEntry> = serde_json::from_str(
+        &fs::read_to_string(&index_path).with_context(|| format!("Reading {index_path:?}"))?,
+    )
+    .with_context(|| format!("Parsing {index_path:?}"))?;
+    index
+        .into_iter()
+        .map(|entry| {
+            let image_path = dir.join(format!("{}.png", entry.name));
+            let encoded =
+                fs::read(&image_path).with_context(|| format!("Reading {image_path:?}"))?;
+            Ok(Arc::new(Template::new(
+                entry.name, entry.x, entry.y, encoded,
+            )?))
+        })
+        .collect()
+}
+
+pub fn save_to_dir(dir: &Path, templates: &[Arc<Template>]) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Creating {dir:?}"))?;
+    for template in templates {
+        let image_path = dir.join(format!("{}.png", template.name));
+        fs::write(&image_path, &template.encoded)
+            .with_context(|| format!("Writing {image_path:?}"))?;
+    }
+    let index: Vec<_> = templates
+        .iter()
+        .map(|template| TemplateIndexEntry {
+            name: template.name.clone(),
+            x: template.x,
+            y: template.y,
+        })
+        .collect();
+    let index_path = dir.join(INDEX_FILE_NAME);
+    fs::write(&index_path, serde_json::to_string_pretty(&index)?)
+        .with_context(|| format!("Writing {index_path:?}"))
+}
+
+type TemplateError = (StatusCode, String);
+
+/// Routes to be nested under /templates
+pub fn router() -> Router<Arc<CanvasState>> {
+    Router::new()
+        .route("/", get(get_templates))
+        .route(
+            "/:name",
+            get(get_template_status)
+                .post(post_template)
+                .delete(delete_template),
+        )
+        .route("/:name/image.png", get(get_template_image))
+}
+
+async fn get_templates(
+    State(canvas_state): State<Arc<CanvasState>>,
+) -> Json<Vec<TemplateProgress>> {
+    Json(
+        canvas_state
+            .template_statuses()
+            .await
+            .iter()
+            .map(|status| status.progress.clone())
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+struct TemplateStatusQueryParams {
+    /// Return at most this many mismatches
+    limit: Option<usize>,
+}
+
+async fn get_template_status(
+    State(canvas_state): State<Arc<CanvasState>>,
+    UrlPath(name): UrlPath<String>,
+    Query(params): Query<TemplateStatusQueryParams>,
+) -> Result<Json<TemplateStatus>, TemplateError> {
+    find_template_status(&canvas_state, &name, params.limit)
+        .await
+        .map(Json)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("No template named {name:?} (or it wasn't checked yet)"),
+            )
+        })
+}
+
+/// Get the last status of a template with at most `limit` mismatches
+pub async fn find_template_status(
+    canvas_state: &CanvasState,
+    name: &str,
+    limit: Option<usize>,
+) -> Option<TemplateStatus> {
+    let statuses = canvas_state.template_statuses().await;
+    let status = statuses
+        .iter()
+        .find(|status| status.progress.name == name)?;
+    let limit = limit.unwrap_or(usize::MAX).min(status.mismatches.len());
+    Some(TemplateStatus {
+        progress: status.progress.clone(),
+        mismatches: status.mismatches[..limit].to_vec(),
+    })
+}
+
+async fn get_template_image(
+    State(canvas_state): State<Arc<CanvasState>>,
+    UrlPath(name): UrlPath<String>,
+) -> Result<impl IntoResponse, TemplateError> {
+    let template = canvas_state
+        .templates()
+        .await
+        .into_iter()
+        .find(|template| template.name == name)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No template named {name:?}")))?;
+    Ok((
+        AppendHeaders([(header::CONTENT_TYPE, "image/png")]),
+        template.encoded().to_vec(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct PostTemplateQueryParams {
+    x: u16,
+    y: u16,
+    admin_token: Option<String>,
+}
+
+/// Add a template (PNG as body). Only admins can replace existing ones.
+async fn post_template(
+    State(canvas_state): State<Arc<CanvasState>>,
+    UrlPath(name): UrlPath<String>,
+    Query(params): Query<PostTemplateQueryParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, TemplateError> {
+    let is_admin = crate::admin::is_admin(&headers, params.admin_token.as_deref());
+    let (admin_only, max_templates, max_template_pixels) = {
+        let server_config = SERVER_CONFIG.lock().unwrap();
+        (
+            server_config.templates_admin_only,
+            server_config.max_templates,
+            server_config.max_template_pixels,
+        )
+    };
+    if admin_only && !is_admin {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            String::from("Only admins can add templates on this server!"),
+        ));
+    }
+    let template = Template::new(name.clone(), params.x, params.y, body.to_vec())
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+
+    let mut templates = canvas_state.write_templates().await;
+    let exists = templates.iter().any(|existing| existing.name == name);
+    if exists && !is_admin {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("A template named {name:?} already exists!"),
+        ));
+    }
+    if !exists && !is_admin && templates.len() >= max_templates {
+        return Err((StatusCode::FORBIDDEN, String::from("Too many templates!")));
+    }
+    let total_area: u64 = templates.iter().map(|existing| existing.area()).sum();
+    if !exists && !is_admin && total_area + template.area() > max_template_pixels {
+        return Err((
+            StatusCode::FORBIDDEN,
+            String::from("The templates would cover too many pixels!"),
+        ));
+    }
+    templates.retain(|existing| existing.name != name);
+    templates.push(Arc::new(template));
+    // Release the lock before saving (the template checker reads the templates)
+    drop(templates);
+    info!(
+        "Templates: {} template {name:?} at {}, {}",
+        if exists { "Replaced" } else { "Added" },
+        params.x,
+        params.y
+    );
+    save_templates(canvas_state, None).await?;
+    Ok(if exists {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    })
+}
+
+async fn delete_template(
+    _: RequireAdmin,
+    State(canvas_state): State<Arc<CanvasState>>,
+    UrlPath(name): UrlPath<String>,
+) -> Result<StatusCode, TemplateError> {
+    let mut templates = canvas_state.write_templates().await;
+    if !templates.iter().any(|template| template.name == name) {
+        return Err((StatusCode::NOT_FOUND, format!("No template named {name:?}")));
+    }
+    templates.retain(|template| template.name != name);
+    drop(templates);
+    info!("Templates: Deleted template {name:?}");
+    save_templates(canvas_state, Some(name)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Persist the templates if a templates dir is configured.
+/// Saves the current templates (not a snapshot), so concurrent saves can't
+/// overwrite newer changes with older ones.
+async fn save_templates(
+    canvas_state: Arc<CanvasState>,
+    deleted: Option<String>,
+) -> Result<(), TemplateError> {
+    let Some(dir) = SERVER_CONFIG.lock().unwrap().templates_dir.clone() else {
+        return Ok(());
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        let _saving = SAVE_LOCK.lock().unwrap();
+        save_to_dir(&dir, &canvas_state.blocking_templates())?;
+        if let Some(deleted) = deleted {
+            let image_path = dir.join(format!("{deleted}.png"));
+            fs::remove_file(&image_path).with_context(|| format!("Removing {image_path:?}"))?;
+        }
+        Ok(())
+    })
+    .await
+    .context("Saving templates")
+    .and_then(|result| result);
+    result.map_err(|err| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Applied but failed to save templates: {err:#}"),
+        )
+    })
+}
@@ -0,0 +1,200 @@
+//! Crops (and optionally downscales) canvas frames to the part of the canvas a
+//! client is looking at. Encoded crops are cached, so clients with the same
+//! viewport share the work.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use color_eyre::{eyre::Context, Result};
+use image::{DynamicImage, GenericImageView, ImageFormat, Rgba, RgbaImage};
+use serde::Serialize;
+
+use crate::canvas::{EncodedCanvas, EncodedFrame, CANVASH, CANVASW};
+
+const MAX_DOWNSCALE: u16 = 16;
+/// Decoded frames kept per kind (the newest ones are requested the most)
+const MAX_DECODED_FRAMES: usize = 4;
+/// Oldest crops get dropped first
+const MAX_CACHED_CROPS: usize = 1024;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Viewport {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    /// Each block of downscale x downscale pixels becomes one pixel
+    pub downscale: u16,
+}
+
+impl Viewport {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err(String::from("Viewport can't be empty"));
+        }
+        if self.x as u32 + self.width as u32 > CANVASW as u32
+            || self.y as u32 + self.height as u32 > CANVASH as u32
+        {
+            return Err(format!("Viewport has to be inside {CANVASW}x{CANVASH}"));
+        }
+        if self.downscale == 0 || self.downscale > MAX_DOWNSCALE {
+            return Err(format!("Downscale has to be between 1 and {MAX_DOWNSCALE}"));
+        }
+        Ok(())
+    }
+
+    /// Crop and downscale. The canvas can be smaller than the viewport (active area).
+    /// Each downscaled pixel is the first non-transparent pixel of its block,
+    /// so single changed pixels of delta frames don't get lost.
+    fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let (image_width, image_height) = image.dimensions();
+        let x = (self.x as u32).min(image_width);
+        let y = (self.y as u32).min(image_height);
+        let width = (self.width as u32).min(image_width - x);
+        let height = (self.height as u32).min(image_height - y);
+        let cropped = image.view(x, y, width, height);
+
+        let downscale = self.downscale as u32;
+        let scaled_width = width.div_ceil(downscale).max(1);
+        let scaled_height = height.div_ceil(downscale).max(1);
+        let mut scaled = RgbaImage::new(scaled_width, scaled_height);
+        for (block_x, block_y, pixel) in scaled.enumerate_pixels_mut() {
+            *pixel = (0..downscale)
+                .flat_map(|y_offset| (0..downscale).map(move |x_offset| (x_offset, y_offset)))
+                .map(|(x_offset, y_offset)| {
+                    (
+                        block_x * downscale + x_offset,
+                        block_y * downscale + y_offset,
+                    )
+                })
+                .filter(|(x, y)| *x < width && *y < height)
+                .map(|(x, y)| cropped.get_pixel(x, y))
+                .find(|pixel| pixel[3] != 0)
+                .unwrap_or(Rgba([0, 0, 0, 0]));
+        }
+        if image.color().has_alpha() {
+            DynamicImage::ImageRgba8(scaled)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(scaled).to_rgb8())
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    Delta,
+    Full,
+}
+
+pub struct CroppedFrame {
+    pub png: Vec<u8>,
+    /// Nothing changed inside the viewport (only for delta frames)
+    pub is_empty: bool,
+}
+
+type CropKey = (FrameKind, u64, Viewport);
+
+#[derive(Default)]
+struct CacheInner {
+    decoded: VecDeque<(FrameKind, u64, Arc<DynamicImage>)>,
+    crops: HashMap<CropKey, Arc<CroppedFrame>>,
+    /// Keys of crops, oldest first
+    crop_order: VecDeque<CropKey>,
+}
+
+#[derive(Clone, Default)]
+pub struct ViewportCache {
+    inner: Arc<Mutex<CacheInner>>,
+}
+
+impl ViewportCache {
+    /// Get the frame cropped to the viewport (encoded as png)
+    pub async fn crop(
+        &self,
+        kind: FrameKind,
+        frame: Arc<EncodedFrame>,
+        viewport: Viewport,
+    ) -> Result<Arc<CroppedFrame>> {
+        let key = (kind, frame.seq, viewport);
+        if let Some(cropped) = self.inner.lock().unwrap().crops.get(&key) {
+            return Ok(cropped.clone());
+        }
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let image = cache.decode(kind, &frame)?;
+            let cropped = Arc::new(crop_and_encode(&image, viewport)?);
+            let mut inner = cache.inner.lock().unwrap();
+            if inner.crops.insert(key, cropped.clone()).is_none() {
+                inner.crop_order.push_back(key);
+                while inner.crop_order.len() > MAX_CACHED_CROPS {
+                    let Some(oldest) = inner.crop_order.pop_front() else {
+                        break;
+                    };
+                    inner.crops.remove(&oldest);
+                }
+            }
+            Ok(cropped)
+        })
+        .await
+        .context("Cropping frame")?
+    }
+
+    /// Crop a png without caching (e.g. the censored canvas)
+    pub async fn crop_uncached(png: Vec<u8>, viewport: Viewport) -> Result<CroppedFrame> {
+        tokio::task::spawn_blocking(move || {
+            let image = image::load_from_memory_with_format(&png, ImageFormat::Png)
+                .context("Decoding png")?;
+            crop_and_encode(&image, viewport)
+        })
+        .await
+        .context("Cropping frame")?
+    }
+
+    fn decode(&self, kind: FrameKind, frame: &EncodedFrame) -> Result<Arc<DynamicImage>> {
+        if let Some((_, _, image)) = self
+            .inner
+            .lock()
+            .unwrap()
+            .decoded
+            .iter()
+            .find(|(decoded_kind, seq, _)| *decoded_kind == kind && *seq == frame.seq)
+        {
+            return Ok(image.clone());
+        }
+        let image = Arc::new(
+            image::load_from_memory_with_format(&frame.png, ImageFormat::Png)
+                .context("Decoding frame")?,
+        );
+        let mut inner = self.inner.lock().unwrap();
+        inner.decoded.push_back((kind, frame.seq, image.clone()));
+        while inner
+            .decoded
+            .iter()
+            .filter(|(decoded_kind, _, _)| *decoded_kind == kind)
+            .count()
+            > MAX_DECODED_FRAMES
+        {
+            let oldest = inner
+                .decoded
+                .iter()
+                .position(|(decoded_kind, _, _)| *decoded_kind == kind)
+                .unwrap();
+            inner.decoded.remove(oldest);
+        }
+        Ok(image)
+    }
+}
+
+fn crop_and_encode(image: &DynamicImage, viewport: Viewport) -> Result<CroppedFrame> {
+    let cropped = viewport.apply(image);
+    let is_empty = match &cropped {
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().all(|pixel| pixel[3] == 0),
+        _ => false,
+    };
+    Ok(CroppedFrame {
+        png: EncodedCanvas::encode(&cropped)?,
+        is_empty,
+    })
+}
@@ -1,31 +1,112 @@
 //! Handles connected websockets and defines how it is used.
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        ConnectInfo, State, WebSocketUpgrade,
+        ConnectInfo, Query, State, WebSocketUpgrade,
     },
     http::HeaderMap,
     response::Response,
 };
 use color_eyre::{eyre::Context, Result};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{
+    error::{RecvError, TryRecvError},
+    Receiver,
+};
 
-use crate::canvas::{CanvasState, PpsInfo};
+use crate::canvas::{CanvasState, CooldownEventsReceiver, EncodedFrame, PpsInfo};
+use crate::canvas_stats::CanvasStats;
+use crate::censor::CensoredEndpoint;
+use crate::leaderboard::Leaderboard;
+use crate::pixel_events::{PixelEventBatch, PixelEventEncoding, PixelEventFilter, Region};
+use crate::pixel_provenance::PixelOwner;
+use crate::pps_history::PpsSample;
+use crate::scheduler::CanvasEvent;
+use crate::templates::{TemplateProgress, TemplateStatus};
+use crate::viewport::{FrameKind, Viewport, ViewportCache};
 
 /// Client -> Server
 #[derive(Deserialize)]
 #[serde(tag = "request", rename_all = "snake_case")]
 enum WsRequest {
     GetFullCanvasOnce,
-    DeltaCanvasStream { enabled: bool },
-    PpsUpdates { enabled: bool },
-    WsCountUpdates { enabled: bool },
+    /// With `sequenced`, each delta frame is announced with its seq. Clients can
+    /// get the frames they missed after reconnecting with `resume_from` (the last
+    /// seq they got), which implies `sequenced`.
+    DeltaCanvasStream {
+        enabled: bool,
+        #[serde(default)]
+        sequenced: bool,
+        resume_from: Option<u64>,
+    },
+    PpsUpdates {
+        enabled: bool,
+    },
+    WsCountUpdates {
+        enabled: bool,
+    },
     GetWsCountUpdateOnce,
-    NudityUpdates { enabled: bool },
+    NudityUpdates {
+        enabled: bool,
+    },
     GetNudityUpdateOnce,
+    CooldownUpdates {
+        enabled: bool,
+    },
+    GetCooldownUpdateOnce,
+    GetPixelInfo {
+        x: u16,
+        y: u16,
+    },
+    HeatmapStream {
+        enabled: bool,
+    },
+    /// Optionally receive stats less often than the server refreshes them
+    StatsUpdates {
+        enabled: bool,
+        min_interval_ms: Option<u64>,
+    },
+    GetStatsOnce,
+    LeaderboardUpdates {
+        enabled: bool,
+    },
+    GetLeaderboardOnce,
+    TemplateUpdates {
+        enabled: bool,
+    },
+    CanvasEventUpdates {
+        enabled: bool,
+    },
+    /// Only get the part of the canvas inside the viewport (optionally downscaled)
+    /// in canvas and delta frames
+    SetViewport {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        downscale: Option<u16>,
+    },
+    ClearViewport,
+    /// Pixel writes (batched per canvas update), optionally only inside
+    /// a region or of some users
+    PixelEvents {
+        enabled: bool,
+        encoding: Option<PixelEventEncoding>,
+        region: Option<Region>,
+        user_ids: Option<Vec<u64>>,
+    },
+    /// Optionally only get the first `limit` mismatches
+    GetTemplateStatus {
+        name: String,
+        limit: Option<usize>,
+    },
 }
 
 /// Server -> Client
@@ -36,28 +117,118 @@ enum WsMessage {
         #[serde(flatten)]
         pps_info: PpsInfo,
     },
+    /// Sent when enabling pps updates (samples of each second, oldest first)
+    PpsHistory {
+        seconds: Vec<PpsSample>,
+    },
     WsCountUpdate {
         ws_connections: usize,
     },
     NudityUpdate {
         is_nude: bool,
     },
+    CooldownUpdate {
+        /// None if the user isn't known (yet)
+        user_id: Option<u64>,
+        remaining_ms: u64,
+    },
+    PixelInfo {
+        #[serde(flatten)]
+        pixel_owner: PixelOwner,
+    },
+    /// The next binary message is a heatmap (not a canvas)
+    HeatmapFrame,
+    /// The next binary message is a delta canvas (only for sequenced delta streams)
+    DeltaFrame {
+        seq: u64,
+        /// Unix time in milliseconds
+        at_ms: u64,
+    },
+    /// The next binary message is a full canvas which includes all deltas
+    /// up to seq (only for sequenced delta streams)
+    FullCanvasFrame {
+        seq: u64,
+    },
+    StatsUpdate {
+        #[serde(flatten)]
+        stats: CanvasStats,
+    },
+    LeaderboardUpdate {
+        #[serde(flatten)]
+        leaderboard: Leaderboard,
+    },
+    CanvasEvent {
+        #[serde(flatten)]
+        canvas_event: CanvasEvent,
+    },
+    TemplateUpdate {
+        templates: Vec<TemplateProgress>,
+    },
+    TemplateStatus {
+        name: String,
+        /// None if there is no such template (or it wasn't checked yet)
+        status: Option<TemplateStatus>,
+    },
+    PixelEvents {
+        #[serde(flatten)]
+        batch: PixelEventBatch,
+    },
+    /// The next binary message is a batch of pixel events (binary encoding)
+    PixelEventFrame,
+    /// Sent when the viewport was set or cleared (None)
+    Viewport {
+        viewport: Option<Viewport>,
+    },
+    /// The connection was too slow and missed updates of an enabled stream.
+    /// For the delta canvas, the next binary message is a full canvas.
+    Resync {
+        stream: ResyncStream,
+        skipped: u64,
+    },
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ResyncStream {
+    DeltaCanvas,
+    Heatmap,
+    Pps,
+    WsCount,
+    Nudity,
+    Cooldown,
+    Stats,
+    Templates,
+    CanvasEvents,
+    Leaderboard,
+    PixelEvents,
+}
+
+#[derive(Deserialize)]
+pub struct WsQueryParams {
+    admin_token: Option<String>,
 }
 
 pub async fn get_ws(
     ws: WebSocketUpgrade,
     State(canvas_state): State<Arc<CanvasState>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<WsQueryParams>,
     headers: HeaderMap,
 ) -> Response {
     // This IP:Port combination will not be bogus (port may not be from real ip),
     // but should serve as a somewhat decent identifier for connections.
     let addr = SocketAddr::new(crate::get_real_ip(addr.ip(), &headers), addr.port());
-    ws.on_upgrade(move |ws| on_websocket_upgrade(ws, canvas_state, addr))
+    let is_admin = crate::admin::is_admin(&headers, params.admin_token.as_deref());
+    ws.on_upgrade(move |ws| on_websocket_upgrade(ws, canvas_state, addr, is_admin))
 }
 
-async fn on_websocket_upgrade(mut ws: WebSocket, canvas_state: Arc<CanvasState>, addr: SocketAddr) {
-    if let Err(err) = websocket_connection(&mut ws, canvas_state, addr).await {
+async fn on_websocket_upgrade(
+    mut ws: WebSocket,
+    canvas_state: Arc<CanvasState>,
+    addr: SocketAddr,
+    is_admin: bool,
+) {
+    if let Err(err) = websocket_connection(&mut ws, canvas_state, addr, is_admin).await {
         warn!("Websocket: Connection to {addr} failed: {err}");
         ws.close().await.ok();
     }
@@ -67,43 +238,188 @@ async fn websocket_connection(
     ws: &mut WebSocket,
     canvas_state: Arc<CanvasState>,
     addr: SocketAddr,
+    is_admin: bool,
 ) -> Result<()> {
-    info!("Websocket: {addr} connected");
+    info!(
+        "Websocket: {addr} connected{}",
+        if is_admin { " as admin" } else { "" }
+    );
     let _ws_tracker = canvas_state.track_new_websocket();
 
     let mut delta_canvas_receiver = canvas_state.read_encoded_delta_canvas().await.subscribe();
+    let mut heatmap_receiver = canvas_state.read_encoded_heatmap().await.subscribe();
     let mut pps_receiver = canvas_state.subscribe_to_pps();
     let mut ws_count_receiver = canvas_state.subscribe_to_websocket_count();
     let mut nudity_results_receiver = canvas_state.subscribe_to_nudity_results();
+    let mut stats_receiver = canvas_state.subscribe_to_stats();
+    let mut template_statuses_receiver = canvas_state.subscribe_to_template_statuses();
+    let mut canvas_events_receiver = canvas_state.subscribe_to_canvas_events();
+    let mut leaderboard_receiver = canvas_state.subscribe_to_leaderboard();
+
+    // Deltas and pixel events would show the raw canvas, so they are left out while it's censored
+    let censor = !is_admin && crate::is_censored_endpoint(CensoredEndpoint::WsFullCanvas);
+    let mut canvas_censored = censor && canvas_state.is_censored().await;
 
     let mut delta_canvas_stream_enabled = false;
+    let mut delta_canvas_sequenced = false;
+    // Frames up to this were already sent (e.g. from the backlog)
+    let mut last_delta_seq = 0;
+    let mut viewport: Option<Viewport> = None;
+    // Only subscribed while enabled, so pixel events aren't collected for nobody
+    let mut pixel_events_receiver: Option<Receiver<Arc<PixelEventBatch>>> = None;
+    let mut pixel_events_encoding = PixelEventEncoding::Json;
+    let mut pixel_events_filter = PixelEventFilter::default();
+    let mut heatmap_stream_enabled = false;
     let mut pps_updates_enabled = false;
     let mut ws_count_updates_enabled = false;
     let mut nudity_updates_enabled = false;
+    // Only subscribed while enabled (and only receives events of this client's user)
+    let mut cooldown_events_receiver: Option<CooldownEventsReceiver> = None;
+    let mut stats_updates_enabled = false;
+    let mut stats_min_interval = Duration::ZERO;
+    let mut stats_last_sent_at: Option<Instant> = None;
+    let mut template_updates_enabled = false;
+    let mut canvas_event_updates_enabled = false;
+    let mut leaderboard_updates_enabled = false;
 
     loop {
         tokio::select! {
             encoded_delta_canvas_res = delta_canvas_receiver.recv() => {
-                if delta_canvas_stream_enabled {
-                    ws.send(Message::Binary(encoded_delta_canvas_res.context("Receive encoded delta canvas")?)).await.context("Send encoded delta canvas")?;
+                match encoded_delta_canvas_res {
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Missed deltas can't be applied anymore, so replace them with a full canvas
+                        let skipped = skipped + drain(&mut delta_canvas_receiver).0;
+                        if delta_canvas_stream_enabled {
+                            debug!("Websocket: {addr} lagged behind {skipped} delta canvas frames, sending a full canvas");
+                            send_resync(ws, ResyncStream::DeltaCanvas, skipped).await?;
+                            last_delta_seq = send_full_canvas_frame(ws, &canvas_state, is_admin, delta_canvas_sequenced, viewport).await?;
+                        }
+                    }
+                    encoded_delta_canvas_res => {
+                        let frame = encoded_delta_canvas_res.context("Receive encoded delta canvas")?;
+                        if delta_canvas_stream_enabled && !canvas_censored && frame.seq > last_delta_seq {
+                            send_delta_frame(ws, &canvas_state, frame.clone(), delta_canvas_sequenced, viewport).await?;
+                            last_delta_seq = frame.seq;
+                        }
+                    }
+                }
+            }
+            encoded_heatmap_res = heatmap_receiver.recv() => {
+                if let Some(encoded_heatmap) = latest_or_resync(ws, heatmap_stream_enabled, ResyncStream::Heatmap, encoded_heatmap_res, &mut heatmap_receiver).await.context("Receive encoded heatmap")? {
+                    if heatmap_stream_enabled {
+                        ws.send(Message::Text(serde_json::to_string(&WsMessage::HeatmapFrame).context("Encode heatmap frame")?)).await.context("Send heatmap frame")?;
+                        ws.send(Message::Binary(encoded_heatmap.png.clone())).await.context("Send encoded heatmap")?;
+                    }
                 }
             }
             pps_info_res = pps_receiver.recv() => {
-                if pps_updates_enabled {
-                    let message = WsMessage::PpsUpdate { pps_info: pps_info_res.context("Receive pps update")? };
-                    ws.send(Message::Text(serde_json::to_string(&message).context("Encode pps update")?)).await.context("Send pps update")?;
+                if let Some(pps_info) = latest_or_resync(ws, pps_updates_enabled, ResyncStream::Pps, pps_info_res, &mut pps_receiver).await.context("Receive pps update")? {
+                    if pps_updates_enabled {
+                        let message = WsMessage::PpsUpdate { pps_info };
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode pps update")?)).await.context("Send pps update")?;
+                    }
                 }
             }
             ws_count_res = ws_count_receiver.recv() => {
-                if ws_count_updates_enabled {
-                    let message = WsMessage::WsCountUpdate { ws_connections: ws_count_res.context("Receive ws count update")? };
-                    ws.send(Message::Text(serde_json::to_string(&message).context("Encode ws count update")?)).await.context("Send ws count update")?;
+                if let Some(ws_connections) = latest_or_resync(ws, ws_count_updates_enabled, ResyncStream::WsCount, ws_count_res, &mut ws_count_receiver).await.context("Receive ws count update")? {
+                    if ws_count_updates_enabled {
+                        let message = WsMessage::WsCountUpdate { ws_connections };
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode ws count update")?)).await.context("Send ws count update")?;
+                    }
                 }
             }
             nudity_result_res = nudity_results_receiver.recv() => {
-                if nudity_updates_enabled {
-                    let message = WsMessage::NudityUpdate { is_nude: nudity_result_res.context("Receive nudity update")?.is_nude };
-                    ws.send(Message::Text(serde_json::to_string(&message).context("Encode nudity update")?)).await.context("Send nudity update")?;
+                if let Some(nudity_result) = latest_or_resync(ws, nudity_updates_enabled, ResyncStream::Nudity, nudity_result_res, &mut nudity_results_receiver).await.context("Receive nudity update")? {
+                    if censor {
+                        let was_censored = canvas_censored;
+                        canvas_censored = canvas_state.is_censored().await;
+                        if canvas_censored != was_censored && delta_canvas_stream_enabled {
+                            // Show the censored canvas, or the real one again (deltas were left out meanwhile)
+                            last_delta_seq = send_full_canvas_frame(ws, &canvas_state, is_admin, delta_canvas_sequenced, viewport).await?;
+                        }
+                    }
+                    if nudity_updates_enabled {
+                        let message = WsMessage::NudityUpdate { is_nude: nudity_result.is_nude };
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode nudity update")?)).await.context("Send nudity update")?;
+                    }
+                }
+            }
+            cooldown_event_res = async { cooldown_events_receiver.as_mut().unwrap().recv().await }, if cooldown_events_receiver.is_some() => {
+                match cooldown_event_res {
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Skip the missed events and look up the current cooldown instead
+                        let skipped = skipped + drain(cooldown_events_receiver.as_mut().unwrap()).0;
+                        send_resync(ws, ResyncStream::Cooldown, skipped).await?;
+                        let message = current_cooldown_update(addr.ip());
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode cooldown update")?)).await.context("Send cooldown update")?;
+                    }
+                    cooldown_event_res => {
+                        let cooldown_event = cooldown_event_res.context("Receive cooldown event")?;
+                        let message = WsMessage::CooldownUpdate { user_id: Some(cooldown_event.user_id), remaining_ms: cooldown_event.remaining.as_millis() as u64 };
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode cooldown update")?)).await.context("Send cooldown update")?;
+                    }
+                }
+            }
+            stats_res = stats_receiver.recv() => {
+                if let Some(stats) = latest_or_resync(ws, stats_updates_enabled, ResyncStream::Stats, stats_res, &mut stats_receiver).await.context("Receive stats update")? {
+                    if stats_updates_enabled && stats_last_sent_at.map(|sent_at| sent_at.elapsed() >= stats_min_interval).unwrap_or(true) {
+                        stats_last_sent_at = Some(Instant::now());
+                        let message = WsMessage::StatsUpdate { stats: stats.as_ref().clone() };
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode stats update")?)).await.context("Send stats update")?;
+                    }
+                }
+            }
+            template_statuses_res = template_statuses_receiver.recv() => {
+                if let Some(template_statuses) = latest_or_resync(ws, template_updates_enabled, ResyncStream::Templates, template_statuses_res, &mut template_statuses_receiver).await.context("Receive template update")? {
+                    if template_updates_enabled {
+                        let templates = template_statuses.iter().map(|status| status.progress.clone()).collect();
+                        let message = WsMessage::TemplateUpdate { templates };
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode template update")?)).await.context("Send template update")?;
+                    }
+                }
+            }
+            canvas_event_res = canvas_events_receiver.recv() => {
+                match canvas_event_res {
+                    // Events aren't skipped to the latest one since each of them matters
+                    Err(RecvError::Lagged(skipped)) => if canvas_event_updates_enabled {
+                        send_resync(ws, ResyncStream::CanvasEvents, skipped).await?;
+                    }
+                    canvas_event_res => if canvas_event_updates_enabled {
+                        let message = WsMessage::CanvasEvent { canvas_event: canvas_event_res.context("Receive canvas event")? };
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode canvas event")?)).await.context("Send canvas event")?;
+                    }
+                }
+            }
+            leaderboard_res = leaderboard_receiver.recv() => {
+                if let Some(leaderboard) = latest_or_resync(ws, leaderboard_updates_enabled, ResyncStream::Leaderboard, leaderboard_res, &mut leaderboard_receiver).await.context("Receive leaderboard update")? {
+                    if leaderboard_updates_enabled {
+                        let message = WsMessage::LeaderboardUpdate { leaderboard: leaderboard.as_ref().clone() };
+                        ws.send(Message::Text(serde_json::to_string(&message).context("Encode leaderboard update")?)).await.context("Send leaderboard update")?;
+                    }
+                }
+            }
+            pixel_events_res = async { pixel_events_receiver.as_mut().unwrap().recv().await }, if pixel_events_receiver.is_some() => {
+                match pixel_events_res {
+                    // Each batch matters, so they aren't skipped to the latest one
+                    Err(RecvError::Lagged(skipped)) => send_resync(ws, ResyncStream::PixelEvents, skipped).await?,
+                    pixel_events_res => {
+                        let batch = pixel_events_res.context("Receive pixel events")?;
+                        if canvas_censored {
+                            continue;
+                        }
+                        if let Some(batch) = batch.filter(&pixel_events_filter) {
+                            match pixel_events_encoding {
+                                PixelEventEncoding::Json => {
+                                    let message = WsMessage::PixelEvents { batch };
+                                    ws.send(Message::Text(serde_json::to_string(&message).context("Encode pixel events")?)).await.context("Send pixel events")?;
+                                }
+                                PixelEventEncoding::Binary => {
+                                    ws.send(Message::Text(serde_json::to_string(&WsMessage::PixelEventFrame).context("Encode pixel event frame")?)).await.context("Send pixel event frame")?;
+                                    ws.send(Message::Binary(batch.to_binary())).await.context("Send pixel events")?;
+                                }
+                            }
+                        }
+                    }
                 }
             }
             maybe_ws_message_res = ws.recv() => {
@@ -119,16 +435,76 @@ async fn websocket_connection(
                         match request {
                             WsRequest::GetFullCanvasOnce => {
                                 debug!("Websocket: {addr} requested a full canvas frame");
+                                let censor = !is_admin && crate::is_censored_endpoint(CensoredEndpoint::WsFullCanvas);
                                 ws.send(Message::Binary(
-                                        canvas_state.read_encoded_full_canvas().await.get_encoded(),
+                                        encoded_full_canvas(&canvas_state, censor, viewport).await?,
                                 ))
                                 .await?;
                             },
-                            WsRequest::DeltaCanvasStream { enabled } => {
+                            WsRequest::SetViewport { x, y, width, height, downscale } => {
+                                let new_viewport = Viewport { x, y, width, height, downscale: downscale.unwrap_or(1) };
+                                new_viewport.validate().map_err(|err| color_eyre::eyre::eyre!("Requested invalid viewport {new_viewport:?}: {err}"))?;
+                                debug!("Websocket: {addr} set the viewport to {new_viewport:?}");
+                                viewport = Some(new_viewport);
+                                let message = WsMessage::Viewport { viewport };
+                                ws.send(Message::Text(serde_json::to_string(&message).context("Encode viewport")?)).await.context("Send viewport")?;
+                                if delta_canvas_stream_enabled {
+                                    // Deltas only cover the old viewport
+                                    last_delta_seq = send_full_canvas_frame(ws, &canvas_state, is_admin, delta_canvas_sequenced, viewport).await?;
+                                }
+                            },
+                            WsRequest::PixelEvents { enabled, encoding, region, user_ids } => {
+                                pixel_events_encoding = encoding.unwrap_or_default();
+                                pixel_events_filter = PixelEventFilter { region, user_ids };
+                                if !enabled {
+                                    pixel_events_receiver = None;
+                                } else if pixel_events_receiver.is_none() {
+                                    pixel_events_receiver = Some(canvas_state.subscribe_to_pixel_events());
+                                }
+                                debug!("Websocket: {addr} {} pixel events", if enabled { "enabled" } else { "disabled" })
+                            },
+                            WsRequest::ClearViewport => {
+                                debug!("Websocket: {addr} cleared the viewport");
+                                viewport = None;
+                                let message = WsMessage::Viewport { viewport };
+                                ws.send(Message::Text(serde_json::to_string(&message).context("Encode viewport")?)).await.context("Send viewport")?;
+                                if delta_canvas_stream_enabled {
+                                    last_delta_seq = send_full_canvas_frame(ws, &canvas_state, is_admin, delta_canvas_sequenced, viewport).await?;
+                                }
+                            },
+                            WsRequest::DeltaCanvasStream { enabled, sequenced, resume_from } => {
                                 delta_canvas_stream_enabled = enabled;
-                                debug!("Websocket: {addr} {} delta canvas frames", if enabled { "enabled" } else { "disabled" })
+                                delta_canvas_sequenced = sequenced || resume_from.is_some();
+                                debug!("Websocket: {addr} {} delta canvas frames", if enabled { "enabled" } else { "disabled" });
+                                if enabled && delta_canvas_sequenced {
+                                    let missed_frames = match resume_from {
+                                        Some(seq) => canvas_state.read_encoded_delta_canvas().await.frames_since(seq),
+                                        None => None,
+                                    };
+                                    match missed_frames {
+                                        Some(missed_frames) if !canvas_censored => {
+                                            debug!("Websocket: {addr} resumed from {resume_from:?} ({} missed delta frames)", missed_frames.len());
+                                            for frame in missed_frames {
+                                                last_delta_seq = frame.seq;
+                                                send_delta_frame(ws, &canvas_state, frame, true, viewport).await?;
+                                            }
+                                        }
+                                        _ => {
+                                            last_delta_seq = send_full_canvas_frame(ws, &canvas_state, is_admin, true, viewport).await?;
+                                        }
+                                    }
+                                }
+                            },
+                            WsRequest::HeatmapStream { enabled } => {
+                                heatmap_stream_enabled = enabled;
+                                debug!("Websocket: {addr} {} heatmap frames", if enabled { "enabled" } else { "disabled" })
                             },
                             WsRequest::PpsUpdates { enabled } => {
+                                if enabled && !pps_updates_enabled {
+                                    let seconds = canvas_state.pps_history().await.seconds.iter().cloned().collect();
+                                    let message = WsMessage::PpsHistory { seconds };
+                                    ws.send(Message::Text(serde_json::to_string(&message).context("Encode pps history")?)).await.context("Send pps history")?;
+                                }
                                 pps_updates_enabled = enabled;
                                 debug!("Websocket: {addr} {} pps updates", if enabled { "enabled" } else { "disabled" })
                             },
@@ -150,6 +526,61 @@ async fn websocket_connection(
                                 let message = WsMessage::NudityUpdate { is_nude: canvas_state.nudity_result().await.is_nude };
                                 ws.send(Message::Text(serde_json::to_string(&message).context("Encode nudity update")?)).await.context("Send nudity update")?;
                             },
+                            WsRequest::CooldownUpdates { enabled } => {
+                                cooldown_events_receiver = match addr.ip() {
+                                    IpAddr::V6(client_ip) if enabled => Some(cooldown_events_receiver.take().unwrap_or_else(|| canvas_state.subscribe_to_cooldown_events(client_ip))),
+                                    // Clients connecting over ipv4 can't be users
+                                    _ => None,
+                                };
+                                debug!("Websocket: {addr} {} cooldown updates", if enabled { "enabled" } else { "disabled" })
+                            },
+                            WsRequest::GetPixelInfo { x, y } => {
+                                let pixel_owner = canvas_state.pixel_provenance().get(x, y).ok_or_else(|| color_eyre::eyre::eyre!("Requested info for pixel {x}, {y} outside of the canvas"))?;
+                                let message = WsMessage::PixelInfo { pixel_owner };
+                                ws.send(Message::Text(serde_json::to_string(&message).context("Encode pixel info")?)).await.context("Send pixel info")?;
+                            },
+                            WsRequest::StatsUpdates { enabled, min_interval_ms } => {
+                                stats_updates_enabled = enabled;
+                                stats_min_interval = Duration::from_millis(min_interval_ms.unwrap_or(0));
+                                stats_last_sent_at = None;
+                                debug!("Websocket: {addr} {} stats updates", if enabled { "enabled" } else { "disabled" })
+                            },
+                            WsRequest::GetStatsOnce => {
+                                debug!("Websocket: {addr} requested stats once");
+                                if let Some(stats) = canvas_state.stats().await {
+                                    let message = WsMessage::StatsUpdate { stats: stats.as_ref().clone() };
+                                    ws.send(Message::Text(serde_json::to_string(&message).context("Encode stats update")?)).await.context("Send stats update")?;
+                                }
+                            },
+                            WsRequest::LeaderboardUpdates { enabled } => {
+                                leaderboard_updates_enabled = enabled;
+                                debug!("Websocket: {addr} {} leaderboard updates", if enabled { "enabled" } else { "disabled" })
+                            },
+                            WsRequest::GetLeaderboardOnce => {
+                                debug!("Websocket: {addr} requested leaderboard once");
+                                if let Some(leaderboard) = canvas_state.leaderboard().await {
+                                    let message = WsMessage::LeaderboardUpdate { leaderboard: leaderboard.as_ref().clone() };
+                                    ws.send(Message::Text(serde_json::to_string(&message).context("Encode leaderboard update")?)).await.context("Send leaderboard update")?;
+                                }
+                            },
+                            WsRequest::CanvasEventUpdates { enabled } => {
+                                canvas_event_updates_enabled = enabled;
+                                debug!("Websocket: {addr} {} canvas event updates", if enabled { "enabled" } else { "disabled" })
+                            },
+                            WsRequest::TemplateUpdates { enabled } => {
+                                template_updates_enabled = enabled;
+                                debug!("Websocket: {addr} {} template updates", if enabled { "enabled" } else { "disabled" })
+                            },
+                            WsRequest::GetTemplateStatus { name, limit } => {
+                                let status = crate::templates::find_template_status(&canvas_state, &name, limit).await;
+                                let message = WsMessage::TemplateStatus { name, status };
+                                ws.send(Message::Text(serde_json::to_string(&message).context("Encode template status")?)).await.context("Send template status")?;
+                            },
+                            WsRequest::GetCooldownUpdateOnce => {
+                                debug!("Websocket: {addr} requested cooldown once");
+                                let message = current_cooldown_update(addr.ip());
+                                ws.send(Message::Text(serde_json::to_string(&message).context("Encode cooldown update")?)).await.context("Send cooldown update")?;
+                            },
                         }
                     }
                     _ => {}
@@ -158,3 +589,194 @@ async fn websocket_connection(
         }
     }
 }
+
+/// Send a delta frame (announced with its seq if sequenced), cropped to the viewport if set.
+/// Deltas without changes inside the viewport are skipped if not sequenced.
+async fn send_delta_frame(
+    ws: &mut WebSocket,
+    canvas_state: &CanvasState,
+    frame: Arc<EncodedFrame>,
+    sequenced: bool,
+    viewport: Option<Viewport>,
+) -> Result<()> {
+    let png = match viewport {
+        Some(viewport) => {
+            let cropped = canvas_state
+                .viewport_cache()
+                .crop(FrameKind::Delta, frame.clone(), viewport)
+                .await?;
+            if cropped.is_empty && !sequenced {
+                return Ok(());
+            }
+            cropped.png.clone()
+        }
+        None => frame.png.clone(),
+    };
+    if sequenced {
+        let message = WsMessage::DeltaFrame {
+            seq: frame.seq,
+            at_ms: frame.at_ms,
+        };
+        ws.send(Message::Text(
+            serde_json::to_string(&message).context("Encode delta frame")?,
+        ))
+        .await
+        .context("Send delta frame")?;
+    }
+    ws.send(Message::Binary(png))
+        .await
+        .context("Send encoded delta canvas")
+}
+
+/// The full canvas (or the censored one), cropped to the viewport if set
+async fn encoded_full_canvas(
+    canvas_state: &CanvasState,
+    censor: bool,
+    viewport: Option<Viewport>,
+) -> Result<Vec<u8>> {
+    let Some(viewport) = viewport else {
+        return Ok(canvas_state.get_encoded_full_canvas(censor).await);
+    };
+    if censor {
+        if let Some(censored) = canvas_state.encoded_censored_full_canvas().await {
+            return Ok(ViewportCache::crop_uncached(censored, viewport).await?.png);
+        }
+    }
+    let frame = canvas_state.read_encoded_full_canvas().await.current();
+    let cropped = canvas_state
+        .viewport_cache()
+        .crop(FrameKind::Full, frame, viewport)
+        .await?;
+    Ok(cropped.png.clone())
+}
+
+/// Send a full canvas to start over with the delta frames.
+/// Returns the seq of the last delta frame included in it.
+async fn send_full_canvas_frame(
+    ws: &mut WebSocket,
+    canvas_state: &CanvasState,
+    is_admin: bool,
+    sequenced: bool,
+    viewport: Option<Viewport>,
+) -> Result<u64> {
+    // The full canvas is updated before the delta canvas, so it includes at least this frame
+    let seq = canvas_state.read_encoded_delta_canvas().await.current().seq;
+    let censor = !is_admin && crate::is_censored_endpoint(CensoredEndpoint::WsFullCanvas);
+    let encoded_full_canvas = encoded_full_canvas(canvas_state, censor, viewport).await?;
+    if sequenced {
+        let message = WsMessage::FullCanvasFrame { seq };
+        ws.send(Message::Text(
+            serde_json::to_string(&message).context("Encode full canvas frame")?,
+        ))
+        .await
+        .context("Send full canvas frame")?;
+    }
+    ws.send(Message::Binary(encoded_full_canvas))
+        .await
+        .context("Send full canvas")?;
+    Ok(seq)
+}
+
+async fn send_resync(ws: &mut WebSocket, stream: ResyncStream, skipped: u64) -> Result<()> {
+    let message = WsMessage::Resync { stream, skipped };
+    ws.send(Message::Text(
+        serde_json::to_string(&message).context("Encode resync")?,
+    ))
+    .await
+    .context("Send resync")
+}
+
+/// Receive everything which is currently queued.
+/// Returns how many were skipped and the latest one.
+fn drain<T: Clone>(receiver: &mut Receiver<T>) -> (u64, Option<T>) {
+    let mut skipped = 0;
+    let mut latest = None;
+    loop {
+        match receiver.try_recv() {
+            Ok(value) => {
+                if latest.replace(value).is_some() {
+                    skipped += 1;
+                }
+            }
+            Err(TryRecvError::Lagged(lagged)) => skipped += lagged,
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return (skipped, latest),
+        }
+    }
+}
+
+/// If the receiver lagged behind, skip to the latest value and tell the client
+/// (if it enabled the stream). Returns None if there is nothing to send.
+async fn latest_or_resync<T: Clone>(
+    ws: &mut WebSocket,
+    enabled: bool,
+    stream: ResyncStream,
+    received: Result<T, RecvError>,
+    receiver: &mut Receiver<T>,
+) -> Result<Option<T>> {
+    match received {
+        Ok(value) => Ok(Some(value)),
+        Err(RecvError::Lagged(lagged)) => {
+            let (skipped, latest) = drain(receiver);
+            if enabled {
+                send_resync(ws, stream, lagged + skipped).await?;
+            }
+            Ok(latest)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg_attr(not(feature = "per_user_pps"), allow(unused_variables))]
+fn current_cooldown_update(user_ip: IpAddr) -> WsMessage {
+    #[cfg(feature = "per_user_pps")]
+    if let IpAddr::V6(user_ip) = user_ip {
+        if let Some(user) = crate::per_user_pps::find_user(user_ip) {
+            let remaining = user
+                .cooldown_remaining(std::time::Instant::now())
+                .unwrap_or_default();
+            return WsMessage::CooldownUpdate {
+                user_id: Some(user.user_id.id),
+                remaining_ms: remaining.as_millis() as u64,
+            };
+        }
+    }
+    WsMessage::CooldownUpdate {
+        user_id: None,
+        remaining_ms: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_delta_canvas_stream_requests() {
+        let request: WsRequest = serde_json::from_str(
+            r#"{"request": "delta_canvas_stream", "enabled": true, "resume_from": 42}"#,
+        )
+        .unwrap();
+        let WsRequest::DeltaCanvasStream {
+            enabled,
+            sequenced,
+            resume_from,
+        } = request
+        else {
+            panic!("Parsed as another request");
+        };
+        assert!(enabled && !sequenced);
+        assert_eq!(resume_from, Some(42));
+
+        let request: WsRequest =
+            serde_json::from_str(r#"{"request": "delta_canvas_stream", "enabled": false}"#)
+                .unwrap();
+        assert!(matches!(
+            request,
+            WsRequest::DeltaCanvasStream {
+                enabled: false,
+                sequenced: false,
+                resume_from: None
+            }
+        ));
+    }
+}